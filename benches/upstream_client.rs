@@ -0,0 +1,114 @@
+//! Latency of proxied requests with a client built per request, as the balancer
+//! used to do, against the clients handed out by the shared `ClientRegistry` with
+//! different connection settings.
+//!
+//! Requests go through `ServerClient::send_request`, the path used by the proxy
+//! after the Redis connection guard is taken.
+
+use std::time::Duration;
+
+use axum::{
+    Router,
+    body::to_bytes,
+    http::{HeaderMap, Method},
+    response::IntoResponse as _,
+    routing::get,
+};
+use criterion::{Criterion, criterion_group, criterion_main};
+use load_balancer::middleware::{ClientRegistry, ClientSettings, ServerClient};
+use reqwest::Url;
+use tokio::{net::TcpListener, runtime::Runtime};
+
+/// Starts a local backend answering every request with a short body
+fn start_backend(runtime: &Runtime) -> Url {
+    runtime.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind backend");
+        let addr = listener.local_addr().expect("backend address");
+
+        tokio::spawn(async move {
+            let app = Router::new().route("/", get(|| async { "ok" }));
+            axum::serve(listener, app).await.expect("serve backend");
+        });
+
+        format!("http://{addr}/").parse().expect("backend url")
+    })
+}
+
+/// Connect timeout the balancer uses by default
+const CONNECT_TIMEOUT: Option<Duration> = Some(Duration::from_secs(2));
+
+/// Settings the balancer uses by default
+fn default_settings() -> ClientSettings {
+    ClientSettings {
+        pool_max_idle_per_host: 32,
+        pool_idle_timeout: Some(Duration::from_secs(90)),
+        http2_prior_knowledge: false,
+        tcp_keepalive: Some(Duration::from_secs(60)),
+    }
+}
+
+async fn proxy_get(server: &ServerClient) {
+    let response = server
+        .send_request(Method::GET, server.url.clone(), HeaderMap::new(), None)
+        .await
+        .expect("send request");
+
+    to_bytes(response.into_response().into_body(), usize::MAX)
+        .await
+        .expect("read body");
+}
+
+fn upstream_client(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("tokio runtime");
+    let url = start_backend(&runtime);
+
+    let mut group = c.benchmark_group("upstream_client");
+
+    group.bench_function("client_per_request", |b| {
+        b.to_async(&runtime).iter(|| async {
+            let server = ServerClient {
+                url: url.clone(),
+                client: reqwest::Client::new(),
+            };
+            proxy_get(&server).await
+        })
+    });
+
+    let variants = [
+        ("registry", default_settings()),
+        (
+            "registry_without_idle_pool",
+            ClientSettings {
+                pool_max_idle_per_host: 0,
+                ..default_settings()
+            },
+        ),
+        (
+            "registry_http2",
+            ClientSettings {
+                http2_prior_knowledge: true,
+                ..default_settings()
+            },
+        ),
+    ];
+
+    for (name, settings) in variants {
+        let registry = ClientRegistry::new(settings);
+
+        group.bench_function(name, |b| {
+            b.to_async(&runtime).iter(|| async {
+                let server = registry
+                    .server_client(url.clone(), CONNECT_TIMEOUT)
+                    .expect("server client");
+                proxy_get(&server).await
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, upstream_client);
+criterion_main!(benches);
@@ -0,0 +1,95 @@
+use std::{net::SocketAddr, str::FromStr as _};
+
+use axum::http::{HeaderMap, HeaderName, Uri, header::COOKIE};
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// Part of a request consistent hashing algorithms route on
+#[derive(Clone, Default)]
+pub enum HashKey {
+    /// Original client address, taken from `X-Forwarded-For` once it has been rewritten
+    /// for the trusted proxies
+    #[default]
+    ClientIp,
+    Header(HeaderName),
+    Cookie(String),
+    /// Zero-based segment of the request path
+    PathSegment(usize),
+    QueryParam(String),
+}
+
+impl HashKey {
+    /// Parses `client_ip`, `header:<name>`, `cookie:<name>`, `path_segment:<index>` or `query:<name>`
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let (kind, name) = value
+            .split_once(':')
+            .map_or((value, ""), |(kind, name)| (kind, name.trim()));
+
+        match (kind.trim(), name) {
+            ("client_ip", "") => Ok(HashKey::ClientIp),
+            ("header", name) if !name.is_empty() => {
+                Ok(HashKey::Header(HeaderName::from_str(name)?))
+            }
+            ("cookie", name) if !name.is_empty() => Ok(HashKey::Cookie(name.to_string())),
+            ("path_segment", index) => Ok(HashKey::PathSegment(index.parse()?)),
+            ("query", name) if !name.is_empty() => Ok(HashKey::QueryParam(name.to_string())),
+            _ => anyhow::bail!(
+                "Invalid hash key '{value}', expected client_ip, header:<name>, cookie:<name>, path_segment:<index> or query:<name>"
+            ),
+        }
+    }
+
+    /// Key of a request, if the request carries it
+    pub fn extract(&self, headers: &HeaderMap, uri: &Uri, peer: SocketAddr) -> Option<String> {
+        match self {
+            HashKey::ClientIp => Some(
+                headers
+                    .get(X_FORWARDED_FOR)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.split(',').next())
+                    .map(|ip| ip.trim().to_string())
+                    .unwrap_or_else(|| peer.ip().to_canonical().to_string()),
+            ),
+            HashKey::Header(name) => headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            HashKey::Cookie(name) => headers
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(cookie, _)| cookie == name)
+                .map(|(_, value)| value.to_string()),
+            HashKey::PathSegment(index) => uri
+                .path()
+                .split('/')
+                .filter(|segment| !segment.is_empty())
+                .nth(*index)
+                .map(str::to_string),
+            HashKey::QueryParam(name) => uri
+                .query()?
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(param, _)| param == name)
+                .map(|(_, value)| value.to_string()),
+        }
+    }
+}
+
+/// Stable 64-bit hash, identical on every balancer instance and build
+pub fn hash(bytes: &[u8], seed: u64) -> u64 {
+    // FNV-1a, finished with the MurmurHash3 mixer so nearby inputs spread over the ring
+    let mut hash = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
@@ -1,12 +1,31 @@
-use std::collections::HashMap;
+use futures_util::{
+    FutureExt as _,
+    future::{self, BoxFuture},
+};
 
-use crate::error::Error;
+use crate::{
+    algorithms::{Candidate, LoadBalancingStrategy, RequestContext},
+    error::Error,
+};
 
-pub async fn least_connection(server_loads: HashMap<String, u32>) -> Result<String, Error> {
-    let (url, _) = server_loads
-        .into_iter()
-        .min_by_key(|(_, load)| *load)
-        .ok_or_else(|| Error::NoServerAvailable)?;
+/// Picks the server with the fewest in-flight requests, counting those still in slow
+/// start as proportionally busier
+pub struct LeastConnection;
 
-    Ok(url)
+impl LoadBalancingStrategy for LeastConnection {
+    fn select<'a>(
+        &'a self,
+        candidates: &'a [Candidate],
+        _request: &'a RequestContext<'a>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        let cost = |candidate: &Candidate| f64::from(candidate.load + 1) / candidate.ramp;
+
+        let url = candidates
+            .iter()
+            .min_by(|a, b| cost(a).total_cmp(&cost(b)))
+            .map(|candidate| candidate.url.clone())
+            .ok_or(Error::NoServerAvailable);
+
+        future::ready(url).boxed()
+    }
 }
@@ -1,81 +1,148 @@
-use std::{collections::HashMap, sync::LazyLock};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::error::Error;
+use futures_util::{FutureExt as _, future::BoxFuture};
 
-pub async fn location_based(location: &str) -> Result<String, Error> {
-    SERVERS_LOCATION_MAPPINGS // Probably not the best solution, but it works for now
-        .get(location)
-        .cloned()
-        .ok_or(Error::InternalServerError)
+use crate::{
+    algorithms::{Candidate, LoadBalancingStrategy, RequestContext},
+    db::RedisClient,
+    error::Error,
+};
+
+/// Region used when a location and its fallbacks have no available servers
+pub const GLOBAL_REGION: &str = "global";
+
+/// Region to backend pool mappings and the fallback chain between regions
+#[derive(Default)]
+pub struct LocationMappings {
+    /// Backend URLs serving each region
+    pub pools: HashMap<String, Vec<String>>,
+    /// Region consulted next when a region has no available servers (e.g. `us-east` -> `north-america`)
+    pub fallbacks: HashMap<String, String>,
+}
+
+impl LocationMappings {
+    /// Parses `region=url,url;region=url` pools and `region=parent;region=parent` fallbacks
+    pub fn parse(pools: &str, fallbacks: &str) -> anyhow::Result<Self> {
+        let pools = parse_pairs(pools)?
+            .into_iter()
+            .map(|(region, urls)| {
+                let urls = urls
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(|url| Ok(url.parse::<reqwest::Url>()?.to_string()))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                Ok((region, urls))
+            })
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+
+        let fallbacks = parse_pairs(fallbacks)?.into_iter().collect();
+
+        Ok(Self { pools, fallbacks })
+    }
 }
 
-static SERVERS_LOCATION_MAPPINGS: LazyLock<HashMap<String, String>> = LazyLock::new(|| {
-    HashMap::from([
-        // ======================
-        // North America
-        // ======================
-        ("us".into(), "https://us-east.example.com".into()),
-        ("us-east".into(), "https://us-east.example.com".into()),
-        ("us-central".into(), "https://us-east.example.com".into()),
-        ("ca".into(), "https://us-east.example.com".into()),
-        ("ca-east".into(), "https://us-east.example.com".into()),
-        ("mx".into(), "https://us-east.example.com".into()),
-        ("us-west".into(), "https://us-west.example.com".into()),
-        ("ca-west".into(), "https://us-west.example.com".into()),
-        // ======================
-        // Europe
-        // ======================
-        ("ie".into(), "https://eu-west.example.com".into()),
-        ("uk".into(), "https://eu-west.example.com".into()),
-        ("fr".into(), "https://eu-west.example.com".into()),
-        ("de".into(), "https://eu-west.example.com".into()),
-        ("nl".into(), "https://eu-west.example.com".into()),
-        ("be".into(), "https://eu-west.example.com".into()),
-        ("es".into(), "https://eu-west.example.com".into()),
-        ("pt".into(), "https://eu-west.example.com".into()),
-        ("pl".into(), "https://eu-central.example.com".into()),
-        ("cz".into(), "https://eu-central.example.com".into()),
-        ("at".into(), "https://eu-central.example.com".into()),
-        ("ch".into(), "https://eu-central.example.com".into()),
-        ("hu".into(), "https://eu-central.example.com".into()),
-        // ======================
-        // Africa
-        // ======================
-        ("ng".into(), "https://africa.example.com".into()),
-        ("gh".into(), "https://africa.example.com".into()),
-        ("ke".into(), "https://africa.example.com".into()),
-        ("za".into(), "https://africa.example.com".into()),
-        ("eg".into(), "https://africa.example.com".into()),
-        // ======================
-        // Middle East
-        // ======================
-        ("ae".into(), "https://middle-east.example.com".into()),
-        ("sa".into(), "https://middle-east.example.com".into()),
-        ("qa".into(), "https://middle-east.example.com".into()),
-        ("il".into(), "https://middle-east.example.com".into()),
-        // ======================
-        // Asia
-        // ======================
-        ("in".into(), "https://asia-south.example.com".into()),
-        ("pk".into(), "https://asia-south.example.com".into()),
-        ("bd".into(), "https://asia-south.example.com".into()),
-        ("lk".into(), "https://asia-south.example.com".into()),
-        ("jp".into(), "https://asia-east.example.com".into()),
-        ("kr".into(), "https://asia-east.example.com".into()),
-        ("tw".into(), "https://asia-east.example.com".into()),
-        ("sg".into(), "https://asia-southeast.example.com".into()),
-        ("id".into(), "https://asia-southeast.example.com".into()),
-        ("th".into(), "https://asia-southeast.example.com".into()),
-        ("vn".into(), "https://asia-southeast.example.com".into()),
-        ("ph".into(), "https://asia-southeast.example.com".into()),
-        // ======================
-        // Oceania
-        // ======================
-        ("au".into(), "https://australia.example.com".into()),
-        ("nz".into(), "https://australia.example.com".into()),
-        // ======================
-        // Fallback / Generic
-        // ======================
-        ("global".into(), "https://us-east.example.com".into()),
-    ])
-});
+/// Narrows the servers to the client's region, then balances them with the
+/// secondary strategy
+pub struct LocationBased {
+    redis_client: RedisClient,
+    secondary: Arc<dyn LoadBalancingStrategy>,
+}
+
+impl LocationBased {
+    pub fn new(redis_client: RedisClient, secondary: Arc<dyn LoadBalancingStrategy>) -> Self {
+        Self {
+            redis_client,
+            secondary,
+        }
+    }
+}
+
+impl LoadBalancingStrategy for LocationBased {
+    fn select<'a>(
+        &'a self,
+        candidates: &'a [Candidate],
+        request: &'a RequestContext<'a>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        async move {
+            let available = candidates
+                .iter()
+                .map(|candidate| candidate.url.clone())
+                .collect::<HashSet<_>>();
+            let servers =
+                location_based(self.redis_client.clone(), request.location, &available).await?;
+
+            let candidates = candidates
+                .iter()
+                .filter(|candidate| servers.contains(&candidate.url))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            self.secondary.select(&candidates, request).await
+        }
+        .boxed()
+    }
+
+    fn observe_latency(&self, url: &str, latency: Duration) {
+        self.secondary.observe_latency(url, latency);
+    }
+}
+
+/// Resolves the servers for a location, walking its fallback chain until a region
+/// has at least one of the `available` servers. Every chain ends at [`GLOBAL_REGION`].
+async fn location_based(
+    mut redis_client: RedisClient,
+    location: &str,
+    available: &HashSet<String>,
+) -> Result<HashSet<String>, Error> {
+    let mut region = location.to_lowercase();
+    let mut visited = HashSet::new();
+    let mut known_region = false;
+
+    while visited.insert(region.clone()) {
+        if let Some(pool) = redis_client.get_location_pool(&region).await? {
+            known_region = true;
+
+            let servers = pool
+                .into_iter()
+                .filter(|url| available.contains(url))
+                .collect::<HashSet<_>>();
+
+            if !servers.is_empty() {
+                return Ok(servers);
+            }
+        }
+
+        region = match redis_client.get_location_fallback(&region).await? {
+            Some(fallback) => {
+                known_region = true;
+                fallback
+            }
+            None => GLOBAL_REGION.to_string(),
+        };
+    }
+
+    if known_region {
+        Err(Error::NoServerAvailable)
+    } else {
+        Err(Error::UnknownLocation(location.to_string()))
+    }
+}
+
+fn parse_pairs(value: &str) -> anyhow::Result<Vec<(String, String)>> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').ok_or_else(|| {
+                anyhow::anyhow!("Invalid location mapping '{pair}', expected 'region=value'")
+            })?;
+            Ok((key.trim().to_lowercase(), value.trim().to_string()))
+        })
+        .collect()
+}
@@ -0,0 +1,193 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use futures_util::{
+    FutureExt as _,
+    future::{self, BoxFuture},
+};
+
+use crate::{
+    algorithms::{Candidate, LoadBalancingStrategy, RequestContext, hash_key::hash},
+    error::Error,
+};
+
+/// Maglev consistent hashing, as described in Google's Maglev paper.
+///
+/// Servers take turns claiming slots of a lookup table in the order of their own
+/// permutation, heavier servers taking proportionally more turns. Lookups are a
+/// single table access and only about `1/n` of the keys move when a server changes.
+///
+/// The table holds every server in rotation and is only rebuilt when they or their
+/// weights change. Servers left out of an attempt, such as servers with an open
+/// circuit or already tried, are skipped by moving on to the next slot.
+#[derive(Clone)]
+pub struct Maglev {
+    /// Number of table slots, a prime much larger than the number of servers
+    table_size: usize,
+    /// Table of the last server set, rebuilt when the servers or weights change
+    table: Arc<Mutex<Option<Table>>>,
+}
+
+impl LoadBalancingStrategy for Maglev {
+    fn select<'a>(
+        &'a self,
+        candidates: &'a [Candidate],
+        request: &'a RequestContext<'a>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        let servers = request
+            .hashed_servers(candidates)
+            .iter()
+            .map(|candidate| (candidate.url.clone(), candidate.weight))
+            .collect();
+        let allowed = candidates
+            .iter()
+            .map(|candidate| candidate.url.as_str())
+            .collect();
+
+        future::ready(self.lookup(servers, &allowed, request.key_hash())).boxed()
+    }
+}
+
+struct Table {
+    servers: Vec<(String, u32)>,
+    /// Index of the server owning each slot
+    slots: Vec<usize>,
+}
+
+/// Lookup table slots unless `MAGLEV_TABLE_SIZE` says otherwise
+pub const DEFAULT_MAGLEV_TABLE_SIZE: usize = 65537;
+
+impl Default for Maglev {
+    fn default() -> Self {
+        Self {
+            table_size: DEFAULT_MAGLEV_TABLE_SIZE,
+            table: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Maglev {
+    pub fn new(table_size: usize) -> anyhow::Result<Self> {
+        if !is_prime(table_size) {
+            anyhow::bail!("MAGLEV_TABLE_SIZE must be a prime number, got {table_size}");
+        }
+
+        Ok(Self {
+            table_size,
+            table: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Picks the server of `allowed` owning `key_hash`, or the next slot owned by one,
+    /// in the table of `servers`, which must be sorted
+    fn lookup(
+        &self,
+        servers: Vec<(String, u32)>,
+        allowed: &HashSet<&str>,
+        key_hash: u64,
+    ) -> Result<String, Error> {
+        if servers.is_empty() {
+            return Err(Error::NoServerAvailable);
+        }
+
+        let mut table = self
+            .table
+            .lock()
+            .map_err(|_| Error::Other(anyhow::anyhow!("Maglev table lock poisoned")))?;
+
+        if table.as_ref().is_none_or(|table| table.servers != servers) {
+            *table = Some(self.build(servers));
+        }
+
+        let table = table.as_ref().ok_or(Error::NoServerAvailable)?;
+        let slot = (key_hash % table.slots.len() as u64) as usize;
+
+        table.slots[slot..]
+            .iter()
+            .chain(&table.slots[..slot])
+            .map(|server| &table.servers[*server].0)
+            .find(|url| allowed.contains(url.as_str()))
+            .cloned()
+            .ok_or(Error::NoServerAvailable)
+    }
+
+    fn build(&self, servers: Vec<(String, u32)>) -> Table {
+        let size = self.table_size as u64;
+
+        let permutations = servers
+            .iter()
+            .map(|(url, _)| {
+                let offset = hash(url.as_bytes(), 0) % size;
+                let skip = hash(url.as_bytes(), 1) % (size - 1) + 1;
+                (offset, skip)
+            })
+            .collect::<Vec<_>>();
+
+        let max_weight = servers.iter().map(|(_, w)| *w.max(&1)).max().unwrap_or(1) as f64;
+        let shares = servers
+            .iter()
+            .map(|(_, weight)| f64::from(*weight.max(&1)) / max_weight)
+            .collect::<Vec<_>>();
+
+        let mut next = vec![0u64; servers.len()];
+        let mut credits = vec![0.0; servers.len()];
+        let mut slots = vec![usize::MAX; self.table_size];
+        let mut filled = 0;
+
+        // The heaviest server claims a slot every round, the others in proportion to their weight
+        while filled < self.table_size {
+            for (server, (offset, skip)) in permutations.iter().enumerate() {
+                credits[server] += shares[server];
+                if credits[server] < 1.0 || filled == self.table_size {
+                    continue;
+                }
+                credits[server] -= 1.0;
+
+                loop {
+                    let slot = ((offset + next[server] * skip) % size) as usize;
+                    next[server] += 1;
+
+                    if slots[slot] == usize::MAX {
+                        slots[slot] = server;
+                        filled += 1;
+                        break;
+                    }
+                }
+            }
+        }
+
+        Table { servers, slots }
+    }
+}
+
+fn is_prime(n: usize) -> bool {
+    n >= 2
+        && (2..)
+            .take_while(|d| d * d <= n)
+            .all(|d| !n.is_multiple_of(d))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_excluded_servers_in_the_table() {
+        let strategy = Maglev::default();
+        let routable = ["http://a", "http://b", "http://c"].map(Candidate::for_test);
+        let request = RequestContext {
+            routable: &routable,
+            ..RequestContext::for_test(Some("key"))
+        };
+
+        strategy
+            .select(&routable[..2], &request)
+            .await
+            .expect("server");
+
+        let table = strategy.table.lock().expect("table lock");
+        assert_eq!(table.as_ref().expect("table").servers.len(), 3);
+    }
+}
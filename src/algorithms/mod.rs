@@ -1,68 +1,256 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::http::{HeaderMap, Method, Uri, request::Parts};
 use reqwest::Url;
 
-use crate::{db::RedisClient, error::Error, middleware::ServerClient};
+use crate::{
+    db::RedisClient,
+    error::Error,
+    middleware::{ClientRegistry, ServerClient},
+    services::ServerHealth,
+};
 
+mod hash_key;
 mod least_connection;
 mod location_based;
+mod maglev;
+mod peak_ewma;
+mod power_of_two_choices;
 mod resource_based;
+mod ring_hash;
+mod round_robin;
+mod slow_start;
+mod strategy;
 mod weighted_least_connection;
 mod weighted_response_time;
 
-#[derive(Clone, Default)]
-pub enum Algorithm {
-    #[default]
-    LeastConnection,
-    LocationBased,
-    ResourceBased,
-    WeightedLeastConnection,
-    WeightedResponseTime,
+pub use hash_key::HashKey;
+pub use least_connection::LeastConnection;
+pub use location_based::{LocationBased, LocationMappings};
+pub use maglev::{DEFAULT_MAGLEV_TABLE_SIZE, Maglev};
+pub use peak_ewma::PeakEwma;
+pub use power_of_two_choices::PowerOfTwoChoices;
+pub use resource_based::{ResourceBased, ResourceScoring, ServerResources};
+pub use ring_hash::{DEFAULT_RING_HASH_VNODES, RingHash};
+pub use round_robin::{RoundRobin, SmoothWeightedRoundRobin};
+pub use slow_start::SlowStart;
+pub use strategy::{
+    Candidate, LoadBalancingStrategy, StrategyContext, StrategyFactory, StrategyRegistry,
+};
+pub use weighted_least_connection::WeightedLeastConnection;
+pub use weighted_response_time::WeightedResponseTime;
+
+/// Attributes of the request being balanced
+pub struct RequestContext<'a> {
+    /// Method, URI and headers of the request, after the forwarding headers were added
+    parts: &'a Parts,
+    /// Region of the client, used by the location based algorithm
+    pub location: &'a str,
+    /// Key consistent hashing algorithms route on; requests without one are spread randomly
+    pub hash_key: Option<&'a str>,
+    /// Every server in rotation, including those left out of the candidates of this
+    /// attempt because their circuit is open or they were already tried. Filled in by
+    /// the [`Balancer`], so consistent hashing keeps the same table across attempts.
+    pub routable: &'a [Candidate],
 }
 
-impl From<String> for Algorithm {
-    fn from(algorithm: String) -> Self {
-        match algorithm.as_str() {
-            "least_connection" => Algorithm::LeastConnection,
-            "location_based" | "location" => Algorithm::LocationBased,
-            "resource_based" => Algorithm::ResourceBased,
-            "weighted_least_connection" => Algorithm::WeightedLeastConnection,
-            "weighted_response_time" => Algorithm::WeightedResponseTime,
-            _ => Algorithm::default(),
+impl<'a> RequestContext<'a> {
+    pub fn new(parts: &'a Parts, location: &'a str, hash_key: Option<&'a str>) -> Self {
+        Self {
+            parts,
+            location,
+            hash_key,
+            routable: &[],
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn for_test(hash_key: Option<&'a str>) -> Self {
+        static PARTS: std::sync::LazyLock<Parts> =
+            std::sync::LazyLock::new(|| axum::http::Request::new(()).into_parts().0);
+
+        Self::new(&PARTS, "", hash_key)
+    }
+
+    /// Method of the request
+    pub fn method(&self) -> &Method {
+        &self.parts.method
+    }
+
+    /// URI of the request, before any path rewrite of its upstream pool
+    pub fn uri(&self) -> &Uri {
+        &self.parts.uri
+    }
+
+    /// Headers of the request, including the `X-Forwarded-*` headers added by the balancer
+    pub fn headers(&self) -> &HeaderMap {
+        &self.parts.headers
+    }
+
+    /// Servers a consistent hash table is built from: the servers in rotation when the
+    /// [`Balancer`] provided them, the candidates otherwise
+    pub fn hashed_servers<'b>(&'b self, candidates: &'b [Candidate]) -> &'b [Candidate] {
+        if self.routable.is_empty() {
+            candidates
+        } else {
+            self.routable
+        }
+    }
+
+    /// Hash of the request's hash key, or a random value for requests without one
+    pub fn key_hash(&self) -> u64 {
+        match self.hash_key {
+            Some(key) => hash_key::hash(key.as_bytes(), 0),
+            None => rand::random(),
         }
     }
 }
 
-impl Algorithm {
+/// Balances requests over a set of servers with a [`LoadBalancingStrategy`]
+#[derive(Clone)]
+pub struct Balancer {
+    strategy: Arc<dyn LoadBalancingStrategy>,
+    slow_start: SlowStart,
+    /// URLs of the servers the balancer picks from
+    servers: Arc<HashSet<String>>,
+}
+
+impl Balancer {
+    pub fn new(
+        strategy: Arc<dyn LoadBalancingStrategy>,
+        slow_start: SlowStart,
+        servers: HashSet<String>,
+    ) -> Self {
+        Self {
+            strategy,
+            slow_start,
+            servers: Arc::new(servers),
+        }
+    }
+
+    /// Selects a server among those currently in rotation (see [`Balancer::routable_servers`]).
+    ///
+    /// The returned client comes from the shared `clients` registry, connecting with
+    /// `connect_timeout`.
     pub async fn select_server(
         &self,
         mut redis_client: RedisClient,
-        location: &str,
+        request: &RequestContext<'_>,
+        exclude: &HashSet<String>,
+        clients: &ClientRegistry,
+        connect_timeout: Option<Duration>,
     ) -> Result<ServerClient, Error> {
-        let server_loads = redis_client.get_all_server_load().await?;
+        let routable = self.candidates(&mut redis_client).await?;
+        let candidates = routable
+            .iter()
+            .filter(|candidate| !exclude.contains(&candidate.url))
+            .cloned()
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return Err(Error::NoServerAvailable);
+        }
+
+        let request = RequestContext {
+            routable: &routable,
+            ..*request
+        };
+        let url = self.strategy.select(&candidates, &request).await?;
+        let url = url.parse::<Url>().map_err(|e| Error::Other(e.into()))?;
+
+        clients.server_client(url, connect_timeout)
+    }
+
+    /// Feeds the latency of a completed request to the strategy
+    pub fn observe_latency(&self, url: &str, latency: Duration) {
+        self.strategy.observe_latency(url, latency);
+    }
+
+    /// Snapshot of the servers in rotation, sorted by URL
+    async fn candidates(&self, redis_client: &mut RedisClient) -> Result<Vec<Candidate>, Error> {
+        let mut health = redis_client.get_all_server_health().await?;
+        let server_loads = self
+            .routable_loads(redis_client, &health, &HashSet::new())
+            .await?;
         let weights = redis_client.get_all_server_weights().await?;
+        let latencies = redis_client.get_all_server_mean_latency().await?;
+        let ramps = self
+            .slow_start
+            .ramps(redis_client, server_loads.keys())
+            .await?;
 
-        let url = match self {
-            Algorithm::LeastConnection => least_connection::least_connection(server_loads).await,
-            Algorithm::LocationBased => location_based::location_based(location).await,
-            Algorithm::ResourceBased => unimplemented!(),
-            Algorithm::WeightedLeastConnection => {
-                weighted_least_connection::weighted_least_connection(server_loads, weights).await
-            }
-            Algorithm::WeightedResponseTime => {
-                weighted_response_time::weighted_response_time(
-                    redis_client.get_all_server_mean_latency().await?,
-                    weights,
-                )
-                .await
-            }
-        }?;
-
-        redis_client.update_server_load(&url, 1).await?;
+        let mut candidates = server_loads
+            .into_iter()
+            .map(|(url, load)| Candidate {
+                load,
+                latency: latencies.get(&url).copied(),
+                weight: weights.get(&url).copied().unwrap_or(1),
+                ramp: ramps.get(&url).copied().unwrap_or(1.0),
+                health: health.remove(&url),
+                url,
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| a.url.cmp(&b.url));
 
-        let url = url.parse::<Url>().map_err(|e| Error::Other(e.into()))?;
+        Ok(candidates)
+    }
+
+    /// Loads of the balancer's servers currently in rotation.
+    ///
+    /// Servers marked unhealthy or recovering by the status probes, ejected as outliers,
+    /// or listed in `exclude` are left out; servers that have not been probed yet are
+    /// assumed healthy.
+    pub async fn routable_servers(
+        &self,
+        redis_client: &mut RedisClient,
+        exclude: &HashSet<String>,
+    ) -> Result<HashMap<String, u32>, Error> {
+        let health = redis_client.get_all_server_health().await?;
+
+        self.routable_loads(redis_client, &health, exclude).await
+    }
+
+    async fn routable_loads(
+        &self,
+        redis_client: &mut RedisClient,
+        health: &HashMap<String, ServerHealth>,
+        exclude: &HashSet<String>,
+    ) -> Result<HashMap<String, u32>, Error> {
+        let ejected = redis_client.get_ejected_servers().await?;
+
+        Ok(redis_client
+            .get_all_server_load()
+            .await?
+            .into_iter()
+            .filter(|(url, _)| self.servers.contains(url))
+            .filter(|(url, _)| health.get(url).is_none_or(ServerHealth::is_routable))
+            .filter(|(url, _)| !ejected.contains(url) && !exclude.contains(url))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Request, header::HOST};
+
+    use super::*;
+
+    #[test]
+    fn exposes_the_request_parts() {
+        let (parts, ()) = Request::post("/api/orders?id=7")
+            .header(HOST, "example.com")
+            .body(())
+            .expect("request")
+            .into_parts();
+        let request = RequestContext::new(&parts, "eu-west", None);
 
-        Ok(ServerClient {
-            url,
-            client: reqwest::Client::new(),
-        })
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.uri().path(), "/api/orders");
+        assert_eq!(request.uri().query(), Some("id=7"));
+        assert_eq!(request.headers()[HOST], "example.com");
+        assert_eq!(request.location, "eu-west");
     }
 }
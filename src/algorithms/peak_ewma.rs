@@ -0,0 +1,134 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures_util::{
+    FutureExt as _,
+    future::{self, BoxFuture},
+};
+
+use crate::{
+    algorithms::{
+        Candidate, LoadBalancingStrategy, RequestContext, power_of_two_choices::sample_two,
+    },
+    error::Error,
+};
+
+/// Peak-EWMA balancing, as done by Finagle and linkerd.
+///
+/// Each instance keeps a moving average of the latency it observes per server that
+/// jumps straight up to latency spikes and decays back down over `decay`. Two random
+/// servers are compared on that latency multiplied by their in-flight requests.
+#[derive(Clone)]
+pub struct PeakEwma {
+    decay: Duration,
+    latencies: Arc<Mutex<HashMap<String, Observed>>>,
+}
+
+#[derive(Clone, Copy)]
+struct Observed {
+    /// Latency in milliseconds
+    latency: f64,
+    at: Instant,
+}
+
+impl Observed {
+    /// Latency decayed towards zero for the time since the last sample, so idle
+    /// servers are tried again
+    fn latency_at(&self, now: Instant, decay: Duration) -> f64 {
+        self.latency * decay_weight(now - self.at, decay)
+    }
+}
+
+impl Default for PeakEwma {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(10))
+    }
+}
+
+impl PeakEwma {
+    pub fn new(decay: Duration) -> Self {
+        Self {
+            decay: decay.max(Duration::from_millis(1)),
+            latencies: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Folds the latency of a completed request into the server's average
+    fn observe(&self, url: &str, latency: Duration) {
+        let Ok(mut latencies) = self.latencies.lock() else {
+            return;
+        };
+
+        let now = Instant::now();
+        let sample = latency.as_secs_f64() * 1000.0;
+
+        let latency = match latencies.get(url) {
+            Some(observed) if sample <= observed.latency => {
+                let weight = decay_weight(now - observed.at, self.decay);
+                observed.latency * weight + sample * (1.0 - weight)
+            }
+            _ => sample,
+        };
+
+        latencies.insert(url.to_string(), Observed { latency, at: now });
+    }
+
+    fn pick(&self, candidates: &[Candidate]) -> Result<String, Error> {
+        let latencies = self
+            .latencies
+            .lock()
+            .map_err(|_| Error::Other(anyhow::anyhow!("Peak EWMA lock poisoned")))?;
+
+        let now = Instant::now();
+        let known = candidates
+            .iter()
+            .filter_map(|candidate| latencies.get(&candidate.url))
+            .map(|observed| observed.latency_at(now, self.decay))
+            .collect::<Vec<_>>();
+
+        // Servers without samples yet are assumed to be as fast as the average server
+        let default_latency = if known.is_empty() {
+            1.0
+        } else {
+            known.iter().sum::<f64>() / known.len() as f64
+        };
+
+        let cost = |candidate: &Candidate| {
+            let latency = latencies
+                .get(&candidate.url)
+                .map_or(default_latency, |observed| {
+                    observed.latency_at(now, self.decay)
+                });
+
+            latency.max(f64::MIN_POSITIVE) * f64::from(candidate.load + 1) / candidate.ramp
+        };
+
+        let candidate = sample_two(candidates)?
+            .into_iter()
+            .min_by(|a, b| cost(a).total_cmp(&cost(b)))
+            .ok_or(Error::NoServerAvailable)?;
+
+        Ok(candidate.url.clone())
+    }
+}
+
+impl LoadBalancingStrategy for PeakEwma {
+    fn select<'a>(
+        &'a self,
+        candidates: &'a [Candidate],
+        _request: &'a RequestContext<'a>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        future::ready(self.pick(candidates)).boxed()
+    }
+
+    fn observe_latency(&self, url: &str, latency: Duration) {
+        self.observe(url, latency);
+    }
+}
+
+fn decay_weight(elapsed: Duration, decay: Duration) -> f64 {
+    (-elapsed.as_secs_f64() / decay.as_secs_f64()).exp()
+}
@@ -0,0 +1,43 @@
+use futures_util::{
+    FutureExt as _,
+    future::{self, BoxFuture},
+};
+use rand::seq::IndexedRandom as _;
+
+use crate::{
+    algorithms::{Candidate, LoadBalancingStrategy, RequestContext},
+    error::Error,
+};
+
+/// Picks two distinct servers at random, or the only one when there is a single server
+pub fn sample_two(candidates: &[Candidate]) -> Result<Vec<&Candidate>, Error> {
+    if candidates.is_empty() {
+        return Err(Error::NoServerAvailable);
+    }
+
+    Ok(candidates.choose_multiple(&mut rand::rng(), 2).collect())
+}
+
+/// Compares the in-flight load of two random servers, so concurrent requests do not
+/// all pile onto the same least loaded server
+pub struct PowerOfTwoChoices;
+
+impl LoadBalancingStrategy for PowerOfTwoChoices {
+    fn select<'a>(
+        &'a self,
+        candidates: &'a [Candidate],
+        _request: &'a RequestContext<'a>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        let cost = |candidate: &Candidate| f64::from(candidate.load + 1) / candidate.ramp;
+
+        let url = sample_two(candidates).and_then(|sample| {
+            sample
+                .into_iter()
+                .min_by(|a, b| cost(a).total_cmp(&cost(b)))
+                .map(|candidate| candidate.url.clone())
+                .ok_or(Error::NoServerAvailable)
+        });
+
+        future::ready(url).boxed()
+    }
+}
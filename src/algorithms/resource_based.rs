@@ -1,8 +1,201 @@
-use crate::{error::Error, middleware::StaticServerData};
+use futures_util::{FutureExt as _, future::BoxFuture};
+use serde::{Deserialize, Serialize};
 
-// TODO: Implement resource-based load balancing algorithm
-pub async fn _resource_based(
-    available_servers: &[StaticServerData],
-) -> Result<StaticServerData, Error> {
-    Ok(available_servers[0].clone())
+use crate::{
+    algorithms::{Candidate, LoadBalancingStrategy, RequestContext},
+    db::RedisClient,
+    error::Error,
+};
+
+/// Resource utilisation reported by a backend in its `/status` response.
+///
+/// A report must carry at least one metric, so an unrelated JSON body such as
+/// `{"status":"ok"}` is not taken for an idle server; metrics left out of a report
+/// count as fully utilised.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(try_from = "ReportedResources")]
+pub struct ServerResources {
+    /// CPU utilisation as a fraction between 0 and 1
+    pub cpu: f64,
+    /// Memory utilisation as a fraction between 0 and 1
+    pub memory: f64,
+    /// Number of requests waiting to be processed
+    pub queue_depth: u32,
+}
+
+/// Metrics as they appear in a `/status` response
+#[derive(Deserialize)]
+struct ReportedResources {
+    cpu: Option<f64>,
+    memory: Option<f64>,
+    queue_depth: Option<u32>,
+}
+
+impl TryFrom<ReportedResources> for ServerResources {
+    type Error = &'static str;
+
+    fn try_from(reported: ReportedResources) -> Result<Self, Self::Error> {
+        if reported.cpu.is_none() && reported.memory.is_none() && reported.queue_depth.is_none() {
+            return Err("expected at least one of cpu, memory or queue_depth");
+        }
+
+        Ok(Self {
+            cpu: reported.cpu.unwrap_or(1.0),
+            memory: reported.memory.unwrap_or(1.0),
+            queue_depth: reported.queue_depth.unwrap_or(u32::MAX),
+        })
+    }
+}
+
+/// Weights combining reported metrics into a single utilisation score.
+///
+/// A server's score is `cpu * cpu_weight + memory * memory_weight +
+/// min(queue_depth / queue_capacity, 1) * queue_weight`, normalised by the
+/// sum of the weights. The server with the lowest score has the most headroom.
+#[derive(Clone, Copy)]
+pub struct ResourceScoring {
+    pub cpu_weight: f64,
+    pub memory_weight: f64,
+    pub queue_weight: f64,
+    /// Queue depth at which a server's queue is considered full
+    pub queue_capacity: u32,
+}
+
+impl Default for ResourceScoring {
+    fn default() -> Self {
+        Self {
+            cpu_weight: 0.5,
+            memory_weight: 0.3,
+            queue_weight: 0.2,
+            queue_capacity: 100,
+        }
+    }
+}
+
+impl ResourceScoring {
+    /// Parses `cpu=0.5,memory=0.3,queue=0.2,queue_capacity=100`, keeping defaults for missing keys
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let mut scoring = Self::default();
+
+        for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').ok_or_else(|| {
+                anyhow::anyhow!("Invalid resource scoring '{pair}', expected 'metric=weight'")
+            })?;
+
+            match key.trim() {
+                "cpu" => scoring.cpu_weight = parse_weight(value)?,
+                "memory" => scoring.memory_weight = parse_weight(value)?,
+                "queue" => scoring.queue_weight = parse_weight(value)?,
+                "queue_capacity" => scoring.queue_capacity = value.trim().parse()?,
+                other => anyhow::bail!("Unknown resource scoring metric '{other}'"),
+            }
+        }
+
+        if scoring.cpu_weight + scoring.memory_weight + scoring.queue_weight <= 0.0 {
+            anyhow::bail!("At least one resource scoring weight must be positive");
+        }
+
+        Ok(scoring)
+    }
+
+    fn score(&self, resources: &ServerResources) -> f64 {
+        let queue = f64::from(resources.queue_depth) / f64::from(self.queue_capacity.max(1));
+
+        let weighted = resources.cpu.clamp(0.0, 1.0) * self.cpu_weight
+            + resources.memory.clamp(0.0, 1.0) * self.memory_weight
+            + queue.min(1.0) * self.queue_weight;
+
+        weighted / (self.cpu_weight + self.memory_weight + self.queue_weight)
+    }
+}
+
+/// Picks the server with the lowest utilisation score.
+///
+/// Servers that have not reported their resources are treated as fully utilised,
+/// so they only receive traffic when no other server is available.
+pub struct ResourceBased {
+    redis_client: RedisClient,
+    scoring: ResourceScoring,
+}
+
+impl ResourceBased {
+    pub fn new(redis_client: RedisClient, scoring: ResourceScoring) -> Self {
+        Self {
+            redis_client,
+            scoring,
+        }
+    }
+}
+
+impl LoadBalancingStrategy for ResourceBased {
+    fn select<'a>(
+        &'a self,
+        candidates: &'a [Candidate],
+        _request: &'a RequestContext<'a>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        let mut redis_client = self.redis_client.clone();
+
+        async move {
+            let resources = redis_client.get_all_server_resources().await?;
+
+            let (url, _) = candidates
+                .iter()
+                .map(|candidate| {
+                    let score = resources
+                        .get(&candidate.url)
+                        .map_or(1.0, |r| self.scoring.score(r));
+                    (&candidate.url, score)
+                })
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .ok_or(Error::NoServerAvailable)?;
+
+            Ok(url.clone())
+        }
+        .boxed()
+    }
+}
+
+fn parse_weight(value: &str) -> anyhow::Result<f64> {
+    let weight = value.trim().parse::<f64>()?;
+
+    if weight < 0.0 || !weight.is_finite() {
+        anyhow::bail!("Invalid resource scoring weight '{value}'");
+    }
+
+    Ok(weight)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_reports_without_metrics() {
+        assert!(serde_json::from_str::<ServerResources>(r#"{"status":"ok"}"#).is_err());
+        assert!(serde_json::from_str::<ServerResources>("{}").is_err());
+    }
+
+    #[test]
+    fn counts_missing_metrics_as_fully_utilised() {
+        let resources =
+            serde_json::from_str::<ServerResources>(r#"{"cpu": 0.2}"#).expect("resources");
+        let scoring = ResourceScoring::default();
+
+        assert_eq!(resources.cpu, 0.2);
+        assert_eq!(resources.memory, 1.0);
+        assert!((scoring.score(&resources) - 0.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn round_trips_through_redis() {
+        let resources = serde_json::from_str::<ServerResources>(
+            r#"{"cpu": 0.42, "memory": 0.61, "queue_depth": 3}"#,
+        )
+        .expect("resources");
+        let stored = serde_json::to_string(&resources).expect("serialize");
+        let loaded = serde_json::from_str::<ServerResources>(&stored).expect("deserialize");
+
+        assert_eq!(loaded.queue_depth, 3);
+        assert_eq!(loaded.memory, 0.61);
+    }
 }
@@ -0,0 +1,140 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use futures_util::{
+    FutureExt as _,
+    future::{self, BoxFuture},
+};
+
+use crate::{
+    algorithms::{Candidate, LoadBalancingStrategy, RequestContext, hash_key::hash},
+    error::Error,
+};
+
+/// Ketama-style consistent hash ring.
+///
+/// Each server is placed on the ring `weight * vnodes_per_weight` times and a key
+/// goes to the first server point at or after its hash, so adding or removing a
+/// server only moves the keys next to its points.
+///
+/// The ring holds every server in rotation and is only rebuilt when they or their
+/// weights change. Servers left out of an attempt, such as servers with an open
+/// circuit or already tried, are skipped by moving on to the next point.
+#[derive(Clone)]
+pub struct RingHash {
+    vnodes_per_weight: u32,
+    /// Ring of the last server set, rebuilt when the servers or weights change
+    ring: Arc<Mutex<Option<Ring>>>,
+}
+
+impl LoadBalancingStrategy for RingHash {
+    fn select<'a>(
+        &'a self,
+        candidates: &'a [Candidate],
+        request: &'a RequestContext<'a>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        let servers = request
+            .hashed_servers(candidates)
+            .iter()
+            .map(|candidate| (candidate.url.clone(), candidate.weight))
+            .collect();
+        let allowed = candidates
+            .iter()
+            .map(|candidate| candidate.url.as_str())
+            .collect();
+
+        future::ready(self.lookup(servers, &allowed, request.key_hash())).boxed()
+    }
+}
+
+struct Ring {
+    servers: Vec<(String, u32)>,
+    /// Point hashes and the index of their server, sorted by hash
+    points: Vec<(u64, usize)>,
+}
+
+/// Ring points per unit of weight unless `RING_HASH_VNODES` says otherwise
+pub const DEFAULT_RING_HASH_VNODES: u32 = 100;
+
+impl Default for RingHash {
+    fn default() -> Self {
+        Self::new(DEFAULT_RING_HASH_VNODES)
+    }
+}
+
+impl RingHash {
+    pub fn new(vnodes_per_weight: u32) -> Self {
+        Self {
+            vnodes_per_weight: vnodes_per_weight.max(1),
+            ring: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Picks the first server of `allowed` at or after `key_hash` on the ring of
+    /// `servers`, which must be sorted
+    fn lookup(
+        &self,
+        servers: Vec<(String, u32)>,
+        allowed: &HashSet<&str>,
+        key_hash: u64,
+    ) -> Result<String, Error> {
+        let mut ring = self
+            .ring
+            .lock()
+            .map_err(|_| Error::Other(anyhow::anyhow!("Hash ring lock poisoned")))?;
+
+        if ring.as_ref().is_none_or(|ring| ring.servers != servers) {
+            *ring = Some(self.build(servers));
+        }
+
+        let ring = ring.as_ref().ok_or(Error::NoServerAvailable)?;
+
+        let position = ring.points.partition_point(|(point, _)| *point < key_hash);
+        ring.points[position..]
+            .iter()
+            .chain(&ring.points[..position])
+            .map(|(_, server)| &ring.servers[*server].0)
+            .find(|url| allowed.contains(url.as_str()))
+            .cloned()
+            .ok_or(Error::NoServerAvailable)
+    }
+
+    fn build(&self, servers: Vec<(String, u32)>) -> Ring {
+        let mut points = servers
+            .iter()
+            .enumerate()
+            .flat_map(|(index, (url, weight))| {
+                (0..weight.max(&1) * self.vnodes_per_weight)
+                    .map(move |vnode| (hash(format!("{url}-{vnode}").as_bytes(), 0), index))
+            })
+            .collect::<Vec<_>>();
+        points.sort_unstable();
+
+        Ring { servers, points }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_excluded_servers_in_the_ring() {
+        let strategy = RingHash::default();
+        let routable = ["http://a", "http://b", "http://c"].map(Candidate::for_test);
+        let request = RequestContext {
+            routable: &routable,
+            ..RequestContext::for_test(Some("key"))
+        };
+
+        strategy
+            .select(&routable[..2], &request)
+            .await
+            .expect("server");
+
+        let ring = strategy.ring.lock().expect("ring lock");
+        assert_eq!(ring.as_ref().expect("ring").servers.len(), 3);
+    }
+}
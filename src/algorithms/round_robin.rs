@@ -0,0 +1,89 @@
+use futures_util::{FutureExt as _, future::BoxFuture};
+
+use crate::{
+    algorithms::{Candidate, LoadBalancingStrategy, RequestContext},
+    db::RedisClient,
+    error::Error,
+};
+
+/// Rotates through the servers with a counter shared by all instances in Redis, one
+/// counter per pool
+pub struct RoundRobin {
+    redis_client: RedisClient,
+    pool: String,
+}
+
+impl RoundRobin {
+    pub fn new(redis_client: RedisClient, pool: &str) -> Self {
+        Self {
+            redis_client,
+            pool: pool.to_string(),
+        }
+    }
+}
+
+impl LoadBalancingStrategy for RoundRobin {
+    fn select<'a>(
+        &'a self,
+        candidates: &'a [Candidate],
+        _request: &'a RequestContext<'a>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        let mut redis_client = self.redis_client.clone();
+
+        async move {
+            if candidates.is_empty() {
+                return Err(Error::NoServerAvailable);
+            }
+
+            // Candidates are sorted, so every instance sees the servers in the same order
+            let counter = redis_client.next_round_robin_counter(&self.pool).await?;
+            let index = (counter % candidates.len() as u64) as usize;
+
+            Ok(candidates[index].url.clone())
+        }
+        .boxed()
+    }
+}
+
+/// nginx-style smooth weighted round-robin, with the current weights of each pool kept
+/// in Redis
+pub struct SmoothWeightedRoundRobin {
+    redis_client: RedisClient,
+    pool: String,
+}
+
+impl SmoothWeightedRoundRobin {
+    pub fn new(redis_client: RedisClient, pool: &str) -> Self {
+        Self {
+            redis_client,
+            pool: pool.to_string(),
+        }
+    }
+}
+
+impl LoadBalancingStrategy for SmoothWeightedRoundRobin {
+    fn select<'a>(
+        &'a self,
+        candidates: &'a [Candidate],
+        _request: &'a RequestContext<'a>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        let mut redis_client = self.redis_client.clone();
+
+        async move {
+            let servers = candidates
+                .iter()
+                .map(|candidate| {
+                    // Scaled up so fractional slow start weights survive the integer rotation
+                    let weight = f64::from(candidate.weight.saturating_mul(100)) * candidate.ramp;
+                    (candidate.url.clone(), (weight.round() as u32).max(1))
+                })
+                .collect::<Vec<_>>();
+
+            redis_client
+                .next_smooth_weighted_server(&self.pool, &servers)
+                .await?
+                .ok_or(Error::NoServerAvailable)
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{db::RedisClient, error::Error};
+
+/// Gradual ramp-up of servers that just joined or came back into rotation.
+///
+/// During the window a server's effective weight is
+/// `weight * max(min_weight, progress ^ (1 / aggression))`, so a cold server is not
+/// flooded just because it has the fewest connections.
+#[derive(Clone, Copy)]
+pub struct SlowStart {
+    /// Length of the ramp, zero disables slow start
+    window: Duration,
+    /// Shape of the ramp: 1.0 is linear, higher values ramp faster at first
+    aggression: f64,
+    /// Fraction of the weight a server starts the window with
+    min_weight: f64,
+}
+
+impl Default for SlowStart {
+    fn default() -> Self {
+        Self {
+            window: Duration::ZERO,
+            aggression: 1.0,
+            min_weight: 0.1,
+        }
+    }
+}
+
+impl SlowStart {
+    pub fn new(window: Duration, aggression: f64, min_weight_percent: f64) -> Self {
+        Self {
+            window,
+            aggression: if aggression > 0.0 { aggression } else { 1.0 },
+            min_weight: (min_weight_percent / 100.0).clamp(0.01, 1.0),
+        }
+    }
+
+    /// Weight factors of the servers in `servers` that are still ramping up; servers at
+    /// full weight are left out
+    pub async fn ramps<'a>(
+        &self,
+        redis_client: &mut RedisClient,
+        servers: impl Iterator<Item = &'a String>,
+    ) -> Result<HashMap<String, f64>, Error> {
+        if self.window.is_zero() {
+            return Ok(HashMap::new());
+        }
+
+        let ready_at = redis_client.get_all_server_ready_times().await?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+
+        Ok(servers
+            .filter_map(|url| {
+                let elapsed = now.saturating_sub(*ready_at.get(url)?);
+                let factor = self.factor(Duration::from_millis(elapsed as u64));
+
+                (factor < 1.0).then(|| (url.clone(), factor))
+            })
+            .collect())
+    }
+
+    fn factor(&self, elapsed: Duration) -> f64 {
+        if elapsed >= self.window {
+            return 1.0;
+        }
+
+        let progress = elapsed.as_secs_f64() / self.window.as_secs_f64();
+        progress.powf(1.0 / self.aggression).max(self.min_weight)
+    }
+}
@@ -0,0 +1,240 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use futures_util::future::BoxFuture;
+
+use crate::{
+    algorithms::{
+        LeastConnection, LocationBased, Maglev, PeakEwma, PowerOfTwoChoices, RequestContext,
+        ResourceBased, ResourceScoring, RingHash, RoundRobin, SmoothWeightedRoundRobin,
+        WeightedLeastConnection, WeightedResponseTime,
+    },
+    config::SystemConfig,
+    db::RedisClient,
+    error::Error,
+    services::ServerHealth,
+};
+
+/// Snapshot of a server in rotation
+#[derive(Clone)]
+pub struct Candidate {
+    pub url: String,
+    /// In-flight requests across all balancer instances
+    pub load: u32,
+    /// Mean response time in milliseconds, once latency has been tracked
+    pub latency: Option<u32>,
+    /// Weight from `server_weights`
+    pub weight: u32,
+    /// Share of the weight in effect, below 1.0 while the server is in slow start
+    pub ramp: f64,
+    /// Last probed health, `None` until the first probe
+    pub health: Option<ServerHealth>,
+}
+
+impl Candidate {
+    /// Weight scaled down by slow start
+    pub fn effective_weight(&self) -> f64 {
+        f64::from(self.weight.max(1)) * self.ramp
+    }
+
+    /// Idle, unprobed server of weight 1 at full ramp
+    #[cfg(test)]
+    pub(crate) fn for_test(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            load: 0,
+            latency: None,
+            weight: 1,
+            ramp: 1.0,
+            health: None,
+        }
+    }
+}
+
+/// Strategy picking the server a request is sent to.
+///
+/// Strategies get the servers in rotation sorted by URL, so every balancer instance
+/// sees them in the same order, and return the URL of one of them. They become
+/// selectable with `ALGORITHM` once added to a [`StrategyRegistry`].
+pub trait LoadBalancingStrategy: Send + Sync {
+    fn select<'a>(
+        &'a self,
+        candidates: &'a [Candidate],
+        request: &'a RequestContext<'a>,
+    ) -> BoxFuture<'a, Result<String, Error>>;
+
+    /// Feeds the latency of a completed request to strategies that track it themselves
+    fn observe_latency(&self, _url: &str, _latency: Duration) {}
+}
+
+/// What a strategy is built from
+pub struct StrategyContext<'a> {
+    /// Name of the upstream pool the strategy balances, for state kept per pool
+    pub pool: &'a str,
+    pub config: &'a SystemConfig,
+    pub redis_client: &'a RedisClient,
+    /// Registry the strategy is built by, for strategies wrapping another one
+    pub registry: &'a StrategyRegistry,
+}
+
+pub type StrategyFactory = Box<
+    dyn Fn(&StrategyContext<'_>) -> anyhow::Result<Arc<dyn LoadBalancingStrategy>> + Send + Sync,
+>;
+
+/// Strategies selectable by name
+pub struct StrategyRegistry {
+    factories: BTreeMap<String, StrategyFactory>,
+}
+
+impl Default for StrategyRegistry {
+    /// Registry of the built-in strategies
+    fn default() -> Self {
+        let mut registry = Self {
+            factories: BTreeMap::new(),
+        };
+
+        registry.register("least_connection", |_| Ok(Arc::new(LeastConnection)));
+        for name in ["location_based", "location"] {
+            registry.register(name, |context| {
+                let secondary = match context.config.location_secondary_algorithm.as_str() {
+                    "" => "least_connection",
+                    "location_based" | "location" => {
+                        anyhow::bail!("LOCATION_SECONDARY_ALGORITHM cannot be location based")
+                    }
+                    secondary => secondary,
+                };
+
+                Ok(Arc::new(LocationBased::new(
+                    context.redis_client.clone(),
+                    context.registry.build(
+                        secondary,
+                        context.pool,
+                        context.config,
+                        context.redis_client,
+                    )?,
+                )))
+            });
+        }
+        registry.register("maglev", |context| {
+            Ok(Arc::new(Maglev::new(context.config.maglev_table_size)?))
+        });
+        registry.register("peak_ewma", |context| {
+            Ok(Arc::new(PeakEwma::new(Duration::from_millis(
+                context.config.peak_ewma_decay_ms,
+            ))))
+        });
+        for name in ["power_of_two_choices", "p2c"] {
+            registry.register(name, |_| Ok(Arc::new(PowerOfTwoChoices)));
+        }
+        registry.register("resource_based", |context| {
+            Ok(Arc::new(ResourceBased::new(
+                context.redis_client.clone(),
+                ResourceScoring::parse(&context.config.resource_scoring)?,
+            )))
+        });
+        registry.register("ring_hash", |context| {
+            Ok(Arc::new(RingHash::new(context.config.ring_hash_vnodes)))
+        });
+        registry.register("round_robin", |context| {
+            Ok(Arc::new(RoundRobin::new(
+                context.redis_client.clone(),
+                context.pool,
+            )))
+        });
+        for name in ["weighted_round_robin", "smooth_weighted_round_robin"] {
+            registry.register(name, |context| {
+                Ok(Arc::new(SmoothWeightedRoundRobin::new(
+                    context.redis_client.clone(),
+                    context.pool,
+                )))
+            });
+        }
+        registry.register("weighted_least_connection", |_| {
+            Ok(Arc::new(WeightedLeastConnection))
+        });
+        registry.register("weighted_response_time", |_| {
+            Ok(Arc::new(WeightedResponseTime))
+        });
+
+        registry
+    }
+}
+
+impl StrategyRegistry {
+    /// Adds a strategy, replacing any strategy registered under the same name
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(&StrategyContext<'_>) -> anyhow::Result<Arc<dyn LoadBalancingStrategy>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.factories.insert(name.to_string(), Box::new(factory));
+    }
+
+    /// Builds the strategy registered as `name` for the upstream pool `pool`
+    pub fn build(
+        &self,
+        name: &str,
+        pool: &str,
+        config: &SystemConfig,
+        redis_client: &RedisClient,
+    ) -> Result<Arc<dyn LoadBalancingStrategy>, Error> {
+        let factory = self
+            .factories
+            .get(name)
+            .ok_or_else(|| Error::UnknownStrategy(name.to_string()))?;
+
+        Ok(factory(&StrategyContext {
+            pool,
+            config,
+            redis_client,
+            registry: self,
+        })?)
+    }
+
+    /// Names of the registered strategies, sorted
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(urls: &[&str]) -> Vec<Candidate> {
+        urls.iter().map(|url| Candidate::for_test(url)).collect()
+    }
+
+    #[tokio::test]
+    async fn consistent_hashing_skips_excluded_servers_without_moving_other_keys() {
+        let routable = candidates(&["http://a", "http://b", "http://c"]);
+        let without_b = candidates(&["http://a", "http://c"]);
+
+        let strategies: [(&str, Arc<dyn LoadBalancingStrategy>); 2] = [
+            ("ring_hash", Arc::new(RingHash::default())),
+            ("maglev", Arc::new(Maglev::default())),
+        ];
+
+        for (name, strategy) in strategies {
+            for key in (0..200).map(|key| key.to_string()) {
+                let request = RequestContext {
+                    routable: &routable,
+                    ..RequestContext::for_test(Some(&key))
+                };
+
+                let owner = strategy.select(&routable, &request).await.expect("owner");
+                let fallback = strategy
+                    .select(&without_b, &request)
+                    .await
+                    .expect("fallback");
+
+                if owner == "http://b" {
+                    assert_ne!(fallback, "http://b", "{name}");
+                } else {
+                    assert_eq!(fallback, owner, "{name}");
+                }
+            }
+        }
+    }
+}
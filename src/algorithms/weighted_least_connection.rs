@@ -1,18 +1,30 @@
-use std::collections::HashMap;
+use futures_util::{
+    FutureExt as _,
+    future::{self, BoxFuture},
+};
 
-use crate::error::Error;
+use crate::{
+    algorithms::{Candidate, LoadBalancingStrategy, RequestContext},
+    error::Error,
+};
 
-pub async fn weighted_least_connection(
-    server_loads: HashMap<String, u32>,
-    weights: HashMap<String, u32>,
-) -> Result<String, Error> {
-    let (url, _) = server_loads
-        .into_iter()
-        .min_by_key(|(key, load)| {
-            let weight = weights.get(key).unwrap_or(&1);
-            *load / weight
-        })
-        .ok_or_else(|| Error::NoServerAvailable)?;
+/// Picks the server with the fewest in-flight requests per unit of weight
+pub struct WeightedLeastConnection;
 
-    Ok(url)
+impl LoadBalancingStrategy for WeightedLeastConnection {
+    fn select<'a>(
+        &'a self,
+        candidates: &'a [Candidate],
+        _request: &'a RequestContext<'a>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        let cost = |candidate: &Candidate| f64::from(candidate.load) / candidate.effective_weight();
+
+        let url = candidates
+            .iter()
+            .min_by(|a, b| cost(a).total_cmp(&cost(b)))
+            .map(|candidate| candidate.url.clone())
+            .ok_or(Error::NoServerAvailable);
+
+        future::ready(url).boxed()
+    }
 }
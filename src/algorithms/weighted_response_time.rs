@@ -1,18 +1,84 @@
-use std::collections::HashMap;
-
-use crate::error::Error;
-
-pub async fn weighted_response_time(
-    latencies: HashMap<String, u32>,
-    weights: HashMap<String, u32>,
-) -> Result<String, Error> {
-    let (url, _) = latencies
-        .into_iter()
-        .min_by_key(|(key, latency)| {
-            let weight = weights.get(key).unwrap_or(&1);
-            *latency / weight
-        })
-        .ok_or_else(|| Error::NoServerAvailable)?;
-
-    Ok(url)
+use futures_util::{
+    FutureExt as _,
+    future::{self, BoxFuture},
+};
+
+use crate::{
+    algorithms::{Candidate, LoadBalancingStrategy, RequestContext},
+    error::Error,
+};
+
+/// Picks the server with the lowest mean latency per unit of weight.
+///
+/// Servers without a tracked latency yet, such as every server on a fresh Redis, are
+/// scored with the mean latency of the others so they still receive the requests that
+/// measure them; with no latency tracked at all the choice falls to the weights.
+pub struct WeightedResponseTime;
+
+impl LoadBalancingStrategy for WeightedResponseTime {
+    fn select<'a>(
+        &'a self,
+        candidates: &'a [Candidate],
+        _request: &'a RequestContext<'a>,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        let tracked = candidates
+            .iter()
+            .filter_map(|candidate| candidate.latency)
+            .map(f64::from)
+            .collect::<Vec<_>>();
+        let neutral = if tracked.is_empty() {
+            1.0
+        } else {
+            tracked.iter().sum::<f64>() / tracked.len() as f64
+        };
+
+        let url = candidates
+            .iter()
+            .map(|candidate| {
+                let latency = candidate.latency.map_or(neutral, f64::from);
+                (candidate, latency / candidate.effective_weight())
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(candidate, _)| candidate.url.clone())
+            .ok_or(Error::NoServerAvailable);
+
+        future::ready(url).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(url: &str, latency: Option<u32>, weight: u32) -> Candidate {
+        Candidate {
+            latency,
+            weight,
+            ..Candidate::for_test(url)
+        }
+    }
+
+    async fn select(candidates: &[Candidate]) -> Result<String, Error> {
+        let request = RequestContext::for_test(None);
+        WeightedResponseTime.select(candidates, &request).await
+    }
+
+    #[tokio::test]
+    async fn picks_by_weight_before_any_latency_is_tracked() {
+        let candidates = [candidate("a", None, 1), candidate("b", None, 3)];
+
+        assert_eq!(select(&candidates).await.expect("server"), "b");
+    }
+
+    #[tokio::test]
+    async fn scores_untracked_servers_with_the_mean_latency() {
+        let candidates = [
+            candidate("fast", Some(10), 1),
+            candidate("slow", Some(50), 1),
+            candidate("new", None, 4),
+        ];
+
+        // The newcomer counts as 30 ms over a weight of 4
+        assert_eq!(select(&candidates).await.expect("server"), "new");
+    }
 }
@@ -1,4 +1,7 @@
+use std::net::SocketAddr;
+
 use axum::{Router, routing::get};
+use futures_util::future::join_all;
 use tokio::{net::TcpListener, task::JoinHandle};
 use tower_http::{
     cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer},
@@ -7,8 +10,14 @@ use tower_http::{
 
 use crate::config::State;
 use crate::middleware::request_route;
-use crate::route::health::status;
-use crate::services::{latency_tracker_worker, server_status_worker};
+use crate::route::{
+    admin::{circuit_breakers, metrics, require_admin_token},
+    health::status,
+};
+use crate::services::{
+    connection_reconciler_worker, latency_tracker_worker, outlier_detection_worker,
+    server_status_worker,
+};
 
 type JoinHandleWrapper = JoinHandle<Result<(), std::io::Error>>;
 
@@ -17,6 +26,8 @@ pub struct App {
     main: JoinHandleWrapper,
     server_status_background_worker: JoinHandleWrapper,
     latency_tracker_background_worker: JoinHandleWrapper,
+    connection_reconciler_background_worker: JoinHandleWrapper,
+    outlier_detection_background_worker: JoinHandleWrapper,
 }
 
 impl App {
@@ -24,8 +35,17 @@ impl App {
         state: State,
         listener: TcpListener,
     ) -> Result<App, Box<dyn std::error::Error>> {
+        let admin = Router::new()
+            .route("/admin/circuit-breakers", get(circuit_breakers))
+            .route("/admin/metrics", get(metrics))
+            .route_layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                require_admin_token,
+            ));
+
         let server = Router::new()
             .route("/status", get(status))
+            .merge(admin)
             .layer(
                 CorsLayer::new()
                     .allow_headers(AllowHeaders::any())
@@ -40,18 +60,51 @@ impl App {
             ))
             .with_state(state.clone());
 
-        let main = tokio::spawn(async move { axum::serve(listener, server).await });
+        let main = tokio::spawn(async move {
+            axum::serve(
+                listener,
+                server.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+        });
 
         let redis_conn_1 = state.redis_conn.clone();
         let redis_conn_2 = state.redis_conn.clone();
+        let redis_conn_3 = state.redis_conn.clone();
+        let redis_conn_4 = state.redis_conn.clone();
+        let instance_id = state.instance_id.clone();
+        let latency_tracking = state.latency_tracking;
+        let health_thresholds = state.health_thresholds;
+        let pools = state.routing.pools().to_vec();
+        let outlier_detector = state.outlier_detector.clone();
+        let clients = state.clients.clone();
 
+        // Each pool is probed with its own health check
         let server_status_background_worker = tokio::spawn(async move {
-            let _: () = server_status_worker(redis_conn_1).await;
+            join_all(pools.into_iter().map(|pool| {
+                server_status_worker(
+                    redis_conn_1.clone(),
+                    pool,
+                    health_thresholds,
+                    clients.clone(),
+                )
+            }))
+            .await;
             Ok(())
         });
 
         let latency_tracker_background_worker = tokio::spawn(async move {
-            let _: () = latency_tracker_worker(redis_conn_2).await;
+            let _: () = latency_tracker_worker(redis_conn_2, latency_tracking).await;
+            Ok(())
+        });
+
+        let connection_reconciler_background_worker = tokio::spawn(async move {
+            let _: () = connection_reconciler_worker(redis_conn_3, instance_id).await;
+            Ok(())
+        });
+
+        let outlier_detection_background_worker = tokio::spawn(async move {
+            let _: () = outlier_detection_worker(redis_conn_4, outlier_detector).await;
             Ok(())
         });
 
@@ -59,6 +112,8 @@ impl App {
             main,
             server_status_background_worker,
             latency_tracker_background_worker,
+            connection_reconciler_background_worker,
+            outlier_detection_background_worker,
         })
     }
 
@@ -66,7 +121,9 @@ impl App {
         match tokio::try_join!(
             self.main,
             self.server_status_background_worker,
-            self.latency_tracker_background_worker
+            self.latency_tracker_background_worker,
+            self.connection_reconciler_background_worker,
+            self.outlier_detection_background_worker
         ) {
             Ok(_) => Ok(()),
             Err(err) => Err(err)?,
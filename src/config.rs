@@ -1,19 +1,427 @@
+use std::{sync::Arc, time::Duration};
+
+use ipnet::IpNet;
 use serde::Deserialize;
 
 use crate::{
-    algorithms::Algorithm,
+    algorithms::{
+        Balancer, DEFAULT_MAGLEV_TABLE_SIZE, DEFAULT_RING_HASH_VNODES, HashKey, LocationMappings,
+        SlowStart, StrategyRegistry,
+    },
     db::{self, RedisClient},
-    middleware::StaticServerData,
+    error::{Error, ErrorFormat, ErrorResponder},
+    middleware::{
+        CircuitBreakerConfig, CircuitBreakers, ClientRegistry, ClientSettings, DEFAULT_POOL,
+        PathRewrite, RetryBudget, RetryOn, RetryPolicy, RoutingTable, SessionAffinity,
+        StaticServerData, TimeoutMetrics, TimeoutTable, UpstreamPool, UpstreamTimeouts,
+        parse_pool_settings, parse_pools, parse_trusted_proxies, pool_config, timeout_from_millis,
+    },
+    services::{HealthCheck, HealthThresholds, OutlierDetection, OutlierDetector},
 };
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct SystemConfig {
     pub available_servers: String,
     pub port: u16,
     pub redis_url: String,
+    /// Name of the load balancing strategy in the [`StrategyRegistry`]
+    #[serde(default = "default_algorithm")]
     pub algorithm: String,
     pub trace_level: String,
     pub default_location: String,
+    /// Comma-separated CIDRs whose `X-Forwarded-*`/`Forwarded` headers are trusted
+    #[serde(default)]
+    pub trusted_proxies: String,
+    /// Bearer token required by the `/admin` endpoints, which answer `404` when unset
+    pub admin_token: Option<String>,
+    /// Prefix removed from request paths before forwarding
+    pub strip_prefix: Option<String>,
+    /// Prefix added to request paths before forwarding
+    pub add_prefix: Option<String>,
+    /// How server latency is tracked: `window` (default) or `ewma`
+    #[serde(default = "default_latency_tracking")]
+    pub latency_tracking: String,
+    /// Number of latency samples kept per server in `window` mode
+    #[serde(default = "default_latency_window_size")]
+    pub latency_window_size: usize,
+    /// Maximum age of latency samples in `window` mode
+    pub latency_window_secs: Option<u64>,
+    /// Weight of the newest sample in `ewma` mode, between 0 and 1
+    #[serde(default = "default_latency_ewma_alpha")]
+    pub latency_ewma_alpha: f64,
+    /// Metric weights for the resource based algorithm, e.g. `cpu=0.5,memory=0.3,queue=0.2`
+    #[serde(default)]
+    pub resource_scoring: String,
+    /// Region pools for the location based algorithm, e.g. `us-east=http://a:3001,http://b:3002;eu=http://c:3003`
+    #[serde(default)]
+    pub location_mappings: String,
+    /// Fallback chain between regions, e.g. `us-east=north-america;north-america=global`
+    #[serde(default)]
+    pub location_fallbacks: String,
+    /// Algorithm balancing the servers within a region
+    #[serde(default)]
+    pub location_secondary_algorithm: String,
+    /// Consecutive failed probes before a server is taken out of rotation
+    #[serde(default = "default_health_failure_threshold")]
+    pub health_failure_threshold: u32,
+    /// Consecutive successful probes before an unhealthy server is put back into rotation
+    #[serde(default = "default_health_success_threshold")]
+    pub health_success_threshold: u32,
+    /// `http` (default) or `tcp` for connect-only checks
+    #[serde(default = "default_health_check_type")]
+    pub health_check_type: String,
+    #[serde(default = "default_health_check_method")]
+    pub health_check_method: String,
+    #[serde(default = "default_health_check_path")]
+    pub health_check_path: String,
+    /// Extra request headers, e.g. `Authorization: Bearer token;X-Probe: 1`
+    #[serde(default)]
+    pub health_check_headers: String,
+    /// Status codes and ranges counted as healthy, e.g. `200-299,304`
+    #[serde(default = "default_health_check_expected_status")]
+    pub health_check_expected_status: String,
+    /// Substring the response body must contain
+    pub health_check_body_contains: Option<String>,
+    /// Regex the response body must match
+    pub health_check_body_regex: Option<String>,
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub health_check_timeout_ms: u64,
+    #[serde(default = "default_health_check_interval_secs")]
+    pub health_check_interval_secs: u64,
+    /// Maximum random delay added to each interval
+    #[serde(default)]
+    pub health_check_jitter_ms: u64,
+    #[serde(default = "default_outlier_consecutive_5xx")]
+    pub outlier_consecutive_5xx: u32,
+    #[serde(default = "default_outlier_consecutive_gateway_failure")]
+    pub outlier_consecutive_gateway_failure: u32,
+    #[serde(default = "default_outlier_base_ejection_secs")]
+    pub outlier_base_ejection_secs: u64,
+    #[serde(default = "default_outlier_max_ejection_secs")]
+    pub outlier_max_ejection_secs: u64,
+    #[serde(default = "default_outlier_max_ejection_percent")]
+    pub outlier_max_ejection_percent: u32,
+    #[serde(default = "default_outlier_interval_secs")]
+    pub outlier_interval_secs: u64,
+    #[serde(default = "default_outlier_success_rate_minimum_hosts")]
+    pub outlier_success_rate_minimum_hosts: usize,
+    #[serde(default = "default_outlier_success_rate_request_volume")]
+    pub outlier_success_rate_request_volume: u64,
+    #[serde(default = "default_outlier_success_rate_stdev_factor")]
+    pub outlier_success_rate_stdev_factor: f64,
+    #[serde(default = "default_circuit_breaker_window_size")]
+    pub circuit_breaker_window_size: usize,
+    #[serde(default = "default_circuit_breaker_minimum_calls")]
+    pub circuit_breaker_minimum_calls: usize,
+    /// Failure rate in percent at which a circuit opens
+    #[serde(default = "default_circuit_breaker_failure_rate_threshold")]
+    pub circuit_breaker_failure_rate_threshold: f64,
+    /// Slow call rate in percent at which a circuit opens
+    #[serde(default = "default_circuit_breaker_slow_call_rate_threshold")]
+    pub circuit_breaker_slow_call_rate_threshold: f64,
+    #[serde(default = "default_circuit_breaker_slow_call_duration_ms")]
+    pub circuit_breaker_slow_call_duration_ms: u64,
+    #[serde(default = "default_circuit_breaker_open_secs")]
+    pub circuit_breaker_open_secs: u64,
+    #[serde(default = "default_circuit_breaker_half_open_max_calls")]
+    pub circuit_breaker_half_open_max_calls: usize,
+    /// Retries after the first attempt, each on a server that has not been tried yet
+    #[serde(default = "default_retry_attempts")]
+    pub retry_attempts: u32,
+    /// Failures that are retried, e.g. `connect-failure,timeout,reset,5xx,503`
+    #[serde(default = "default_retry_on")]
+    pub retry_on: String,
+    /// Also retry `POST` and `PATCH` requests
+    #[serde(default)]
+    pub retry_non_idempotent: bool,
+    /// Largest request body buffered so the request can be retried
+    #[serde(default = "default_retry_max_body_bytes")]
+    pub retry_max_body_bytes: usize,
+    /// Concurrent retries allowed, in percent of the active requests
+    #[serde(default = "default_retry_budget_percent")]
+    pub retry_budget_percent: f64,
+    /// Concurrent retries always allowed regardless of the budget
+    #[serde(default = "default_retry_min_concurrency")]
+    pub retry_min_concurrency: usize,
+    /// Time allowed to connect to a backend, `0` disables the timeout
+    #[serde(default = "default_upstream_connect_timeout_ms")]
+    pub upstream_connect_timeout_ms: u64,
+    /// Time allowed for a single attempt to receive the response headers
+    #[serde(default = "default_upstream_per_try_timeout_ms")]
+    pub upstream_per_try_timeout_ms: u64,
+    /// Time allowed for all attempts of a request, retries included
+    #[serde(default = "default_upstream_total_timeout_ms")]
+    pub upstream_total_timeout_ms: u64,
+    /// Longest pause allowed between two chunks of a response body
+    #[serde(default = "default_upstream_idle_timeout_ms")]
+    pub upstream_idle_timeout_ms: u64,
+    /// Per-route overrides, e.g. `/api=per_try:2000,total:5000;/upload=idle:120000`
+    #[serde(default)]
+    pub upstream_route_timeouts: String,
+    /// Named backend pools besides `AVAILABLE_SERVERS`, e.g. `api=http://a:3001|1,http://b:3002|2;static=http://c:3003|1`
+    #[serde(default)]
+    pub upstream_pools: String,
+    /// Per-pool overrides, e.g. `api=algorithm:round_robin,health_check_path:/healthz,per_try_timeout_ms:2000`
+    #[serde(default)]
+    pub upstream_pool_settings: String,
+    /// Routing rules, e.g. `api: host=api.example.com, prefix=/v1; static: regex=^/assets/`
+    #[serde(default)]
+    pub routes: String,
+    /// Idle connections kept open per backend
+    #[serde(default = "default_upstream_pool_max_idle_per_host")]
+    pub upstream_pool_max_idle_per_host: usize,
+    /// Time an idle backend connection is kept open, `0` keeps it until the backend closes it
+    #[serde(default = "default_upstream_pool_idle_timeout_secs")]
+    pub upstream_pool_idle_timeout_secs: u64,
+    /// Speak HTTP/2 to backends without negotiating it first
+    #[serde(default)]
+    pub upstream_http2: bool,
+    /// TCP keepalive interval of backend connections, `0` disables it
+    #[serde(default = "default_upstream_tcp_keepalive_secs")]
+    pub upstream_tcp_keepalive_secs: u64,
+    /// Body format of error responses: `problem_json` (default) or `plain_text`
+    #[serde(default = "default_error_format")]
+    pub error_format: String,
+    /// `Retry-After` sent with `503 Service Unavailable` when no backend is available
+    #[serde(default = "default_error_retry_after_secs")]
+    pub error_retry_after_secs: u64,
+    /// Request key of the consistent hashing algorithms, e.g. `client_ip`, `header:X-User-Id`,
+    /// `cookie:session`, `path_segment:1` or `query:user`
+    #[serde(default = "default_hash_key")]
+    pub hash_key: String,
+    /// Points placed on the hash ring per unit of server weight
+    #[serde(default = "default_ring_hash_vnodes")]
+    pub ring_hash_vnodes: u32,
+    /// Number of slots of the Maglev lookup table, must be prime
+    #[serde(default = "default_maglev_table_size")]
+    pub maglev_table_size: usize,
+    /// Time over which the peak-EWMA latency decays
+    #[serde(default = "default_peak_ewma_decay_ms")]
+    pub peak_ewma_decay_ms: u64,
+    /// Time over which new and recovered servers ramp up to their full weight, 0 disables it
+    #[serde(default)]
+    pub slow_start_window_secs: u64,
+    /// Shape of the ramp: 1.0 is linear, higher values ramp faster at first
+    #[serde(default = "default_slow_start_aggression")]
+    pub slow_start_aggression: f64,
+    /// Share of its weight a server starts the ramp with
+    #[serde(default = "default_slow_start_min_weight_percent")]
+    pub slow_start_min_weight_percent: f64,
+    /// `none` (default), `cookie` or `header`
+    #[serde(default)]
+    pub session_affinity: String,
+    #[serde(default = "default_session_affinity_cookie")]
+    pub session_affinity_cookie: String,
+    #[serde(default = "default_session_affinity_header")]
+    pub session_affinity_header: String,
+    #[serde(default = "default_session_affinity_ttl_secs")]
+    pub session_affinity_ttl_secs: u64,
+    /// Key signing the affinity tokens, shared by all balancer instances
+    pub session_affinity_key: Option<String>,
+}
+
+fn default_algorithm() -> String {
+    "least_connection".to_string()
+}
+
+fn default_latency_tracking() -> String {
+    "window".to_string()
+}
+
+fn default_latency_window_size() -> usize {
+    100
+}
+
+fn default_latency_ewma_alpha() -> f64 {
+    0.2
+}
+
+fn default_health_failure_threshold() -> u32 {
+    3
+}
+
+fn default_health_success_threshold() -> u32 {
+    2
+}
+
+fn default_health_check_type() -> String {
+    "http".to_string()
+}
+
+fn default_health_check_method() -> String {
+    "GET".to_string()
+}
+
+fn default_health_check_path() -> String {
+    "/status".to_string()
+}
+
+fn default_health_check_expected_status() -> String {
+    "200-299".to_string()
+}
+
+fn default_health_check_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    10
+}
+
+fn default_outlier_consecutive_5xx() -> u32 {
+    5
+}
+
+fn default_outlier_consecutive_gateway_failure() -> u32 {
+    5
+}
+
+fn default_outlier_base_ejection_secs() -> u64 {
+    30
+}
+
+fn default_outlier_max_ejection_secs() -> u64 {
+    300
+}
+
+fn default_outlier_max_ejection_percent() -> u32 {
+    10
+}
+
+fn default_outlier_interval_secs() -> u64 {
+    10
+}
+
+fn default_outlier_success_rate_minimum_hosts() -> usize {
+    5
+}
+
+fn default_outlier_success_rate_request_volume() -> u64 {
+    100
+}
+
+fn default_outlier_success_rate_stdev_factor() -> f64 {
+    1.9
+}
+
+fn default_circuit_breaker_window_size() -> usize {
+    20
+}
+
+fn default_circuit_breaker_minimum_calls() -> usize {
+    10
+}
+
+fn default_circuit_breaker_failure_rate_threshold() -> f64 {
+    50.0
+}
+
+fn default_circuit_breaker_slow_call_rate_threshold() -> f64 {
+    100.0
+}
+
+fn default_circuit_breaker_slow_call_duration_ms() -> u64 {
+    5000
+}
+
+fn default_circuit_breaker_open_secs() -> u64 {
+    30
+}
+
+fn default_circuit_breaker_half_open_max_calls() -> usize {
+    3
+}
+
+fn default_retry_attempts() -> u32 {
+    2
+}
+
+fn default_retry_on() -> String {
+    "connect-failure,timeout,502,503,504".to_string()
+}
+
+fn default_retry_max_body_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_retry_budget_percent() -> f64 {
+    20.0
+}
+
+fn default_retry_min_concurrency() -> usize {
+    3
+}
+
+fn default_upstream_connect_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_upstream_per_try_timeout_ms() -> u64 {
+    15000
+}
+
+fn default_upstream_total_timeout_ms() -> u64 {
+    30000
+}
+
+fn default_upstream_idle_timeout_ms() -> u64 {
+    60000
+}
+
+fn default_upstream_pool_max_idle_per_host() -> usize {
+    32
+}
+
+fn default_upstream_pool_idle_timeout_secs() -> u64 {
+    90
+}
+
+fn default_upstream_tcp_keepalive_secs() -> u64 {
+    60
+}
+
+fn default_error_format() -> String {
+    "problem_json".to_string()
+}
+
+fn default_error_retry_after_secs() -> u64 {
+    5
+}
+
+fn default_hash_key() -> String {
+    "client_ip".to_string()
+}
+
+fn default_ring_hash_vnodes() -> u32 {
+    DEFAULT_RING_HASH_VNODES
+}
+
+fn default_maglev_table_size() -> usize {
+    DEFAULT_MAGLEV_TABLE_SIZE
+}
+
+fn default_peak_ewma_decay_ms() -> u64 {
+    10000
+}
+
+fn default_slow_start_aggression() -> f64 {
+    1.0
+}
+
+fn default_slow_start_min_weight_percent() -> f64 {
+    10.0
+}
+
+fn default_session_affinity_cookie() -> String {
+    "lb_affinity".to_string()
+}
+
+fn default_session_affinity_header() -> String {
+    "X-Session-Affinity".to_string()
+}
+
+fn default_session_affinity_ttl_secs() -> u64 {
+    3600
 }
 
 impl SystemConfig {
@@ -22,17 +430,93 @@ impl SystemConfig {
 
         envy::from_env::<Self>().map_err(|e| anyhow::anyhow!("Failed to load configuration: {}", e))
     }
+
+    #[cfg(test)]
+    pub(crate) fn for_test() -> Self {
+        envy::from_iter(
+            [
+                ("AVAILABLE_SERVERS", "http://localhost:3001|1"),
+                ("PORT", "3000"),
+                ("REDIS_URL", "redis://localhost:6379"),
+                ("TRACE_LEVEL", "info"),
+                ("DEFAULT_LOCATION", "us-east"),
+                ("HEALTH_CHECK_BODY_REGEX", "^ok$"),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string())),
+        )
+        .expect("config")
+    }
 }
 
 #[derive(Clone)]
 pub struct State {
     pub redis_conn: RedisClient,
-    pub algorithm: Algorithm,
     pub default_location: String,
+    pub trusted_proxies: Arc<[IpNet]>,
+    /// Unique id of this balancer instance, used to track its share of server load
+    pub instance_id: Arc<str>,
+    pub latency_tracking: LatencyTracking,
+    pub health_thresholds: HealthThresholds,
+    pub outlier_detector: Arc<OutlierDetector>,
+    pub circuit_breakers: Arc<CircuitBreakers>,
+    pub retry_policy: Arc<RetryPolicy>,
+    /// Pooled HTTP clients shared by proxied requests and health checks
+    pub clients: Arc<ClientRegistry>,
+    /// Routes requests to their upstream pool
+    pub routing: Arc<RoutingTable>,
+    pub timeout_metrics: Arc<TimeoutMetrics>,
+    pub error_responder: ErrorResponder,
+    pub hash_key: HashKey,
+    pub session_affinity: Option<Arc<SessionAffinity>>,
+    /// Bearer token required by the `/admin` endpoints
+    pub admin_token: Option<Arc<str>>,
+}
+
+/// Strategy used to turn per-request latency samples into a server's mean latency
+#[derive(Clone, Copy)]
+pub enum LatencyTracking {
+    /// Mean of the most recent `size` samples, optionally ignoring samples older than `max_age`
+    Window {
+        size: usize,
+        max_age: Option<Duration>,
+    },
+    /// Exponentially weighted moving average updated on every sample
+    Ewma { alpha: f64 },
+}
+
+impl LatencyTracking {
+    pub fn from_config(config: &SystemConfig) -> anyhow::Result<Self> {
+        match config.latency_tracking.as_str() {
+            "window" => {
+                if config.latency_window_size == 0 {
+                    anyhow::bail!("LATENCY_WINDOW_SIZE must be greater than 0");
+                }
+
+                Ok(Self::Window {
+                    size: config.latency_window_size,
+                    max_age: config.latency_window_secs.map(Duration::from_secs),
+                })
+            }
+            "ewma" => {
+                if !(config.latency_ewma_alpha > 0.0 && config.latency_ewma_alpha <= 1.0) {
+                    anyhow::bail!("LATENCY_EWMA_ALPHA must be in (0, 1]");
+                }
+
+                Ok(Self::Ewma {
+                    alpha: config.latency_ewma_alpha,
+                })
+            }
+            other => anyhow::bail!("Unknown latency tracking '{other}', expected window or ewma"),
+        }
+    }
 }
 
 impl State {
-    pub async fn new(config: &SystemConfig) -> Result<Self, Box<dyn std::error::Error>> {
+    /// Builds the shared state, with the pools balanced by strategies from `registry`
+    pub async fn new(
+        config: &SystemConfig,
+        registry: StrategyRegistry,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         let servers = config.available_servers.split(',').collect::<Vec<&str>>();
 
         let available_servers: Vec<StaticServerData> = servers
@@ -41,13 +525,169 @@ impl State {
             .map(StaticServerData::new)
             .collect::<Result<Vec<StaticServerData>, _>>()?;
 
-        let redis_conn =
-            db::RedisClient::init_redis(&config.redis_url, available_servers.clone()).await?;
+        let mut pools = vec![(DEFAULT_POOL.to_string(), available_servers)];
+        pools.extend(parse_pools(&config.upstream_pools)?);
+
+        let mut redis_conn = db::RedisClient::init_redis(
+            &config.redis_url,
+            pools
+                .iter()
+                .flat_map(|(_, servers)| servers.iter().cloned())
+                .collect(),
+        )
+        .await?;
+
+        redis_conn
+            .update_location_mappings(&LocationMappings::parse(
+                &config.location_mappings,
+                &config.location_fallbacks,
+            )?)
+            .await?;
+
+        let pool_settings = parse_pool_settings(&config.upstream_pool_settings)?;
+        let pools = pools
+            .into_iter()
+            .map(|(name, servers)| {
+                let settings = pool_settings.get(&name).map_or("", String::as_str);
+                let pool_config = pool_config(config, settings)?;
+                build_pool(name, servers, &pool_config, &registry, &redis_conn)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let pool_servers = pools
+            .iter()
+            .map(|pool| {
+                pool.servers
+                    .iter()
+                    .map(|server| server.url.to_string())
+                    .collect()
+            })
+            .collect();
 
         Ok(State {
+            routing: Arc::new(RoutingTable::parse(&config.routes, pools)?),
             redis_conn,
-            algorithm: config.algorithm.clone().into(),
             default_location: config.default_location.clone(),
+            trusted_proxies: parse_trusted_proxies(&config.trusted_proxies)?.into(),
+            instance_id: new_instance_id().into(),
+            latency_tracking: LatencyTracking::from_config(config)?,
+            health_thresholds: HealthThresholds {
+                failure_threshold: config.health_failure_threshold.max(1),
+                success_threshold: config.health_success_threshold.max(1),
+            },
+            outlier_detector: Arc::new(OutlierDetector::new(
+                OutlierDetection {
+                    consecutive_5xx: config.outlier_consecutive_5xx.max(1),
+                    consecutive_gateway_failure: config.outlier_consecutive_gateway_failure.max(1),
+                    base_ejection_time: Duration::from_secs(config.outlier_base_ejection_secs),
+                    max_ejection_time: Duration::from_secs(config.outlier_max_ejection_secs),
+                    max_ejection_percent: config.outlier_max_ejection_percent.min(100),
+                    interval: Duration::from_secs(config.outlier_interval_secs.max(1)),
+                    success_rate_minimum_hosts: config.outlier_success_rate_minimum_hosts,
+                    success_rate_request_volume: config.outlier_success_rate_request_volume,
+                    success_rate_stdev_factor: config.outlier_success_rate_stdev_factor,
+                },
+                pool_servers,
+            )),
+            circuit_breakers: Arc::new(CircuitBreakers::new(CircuitBreakerConfig {
+                window_size: config.circuit_breaker_window_size.max(1),
+                minimum_calls: config.circuit_breaker_minimum_calls.max(1),
+                failure_rate_threshold: config.circuit_breaker_failure_rate_threshold,
+                slow_call_rate_threshold: config.circuit_breaker_slow_call_rate_threshold,
+                slow_call_duration: Duration::from_millis(
+                    config.circuit_breaker_slow_call_duration_ms,
+                ),
+                open_duration: Duration::from_secs(config.circuit_breaker_open_secs),
+                half_open_max_calls: config.circuit_breaker_half_open_max_calls.max(1),
+            })),
+            retry_policy: Arc::new(RetryPolicy {
+                max_retries: config.retry_attempts,
+                retry_on: RetryOn::parse(&config.retry_on)?,
+                retry_non_idempotent: config.retry_non_idempotent,
+                max_body_bytes: config.retry_max_body_bytes,
+                budget: RetryBudget::new(
+                    config.retry_budget_percent.max(0.0),
+                    config.retry_min_concurrency,
+                ),
+            }),
+            clients: Arc::new(ClientRegistry::new(ClientSettings {
+                pool_max_idle_per_host: config.upstream_pool_max_idle_per_host,
+                pool_idle_timeout: timeout_from_millis(
+                    config.upstream_pool_idle_timeout_secs.saturating_mul(1000),
+                ),
+                http2_prior_knowledge: config.upstream_http2,
+                tcp_keepalive: timeout_from_millis(
+                    config.upstream_tcp_keepalive_secs.saturating_mul(1000),
+                ),
+            })),
+            timeout_metrics: Arc::new(TimeoutMetrics::default()),
+            error_responder: ErrorResponder {
+                format: ErrorFormat::parse(&config.error_format)?,
+                retry_after: Duration::from_secs(config.error_retry_after_secs),
+            },
+            hash_key: HashKey::parse(&config.hash_key)?,
+            session_affinity: SessionAffinity::from_config(config)?.map(Arc::new),
+            admin_token: config
+                .admin_token
+                .as_deref()
+                .filter(|token| !token.is_empty())
+                .map(Arc::from),
         })
     }
 }
+
+/// Builds an upstream pool from the configuration with its settings applied
+fn build_pool(
+    name: String,
+    servers: Vec<StaticServerData>,
+    config: &SystemConfig,
+    registry: &StrategyRegistry,
+    redis_conn: &RedisClient,
+) -> anyhow::Result<UpstreamPool> {
+    let strategy = registry
+        .build(&config.algorithm, &name, config, redis_conn)
+        .map_err(|e| match e {
+            Error::UnknownStrategy(algorithm) => anyhow::anyhow!(
+                "Unknown algorithm '{algorithm}' for pool '{name}', expected one of: {}",
+                registry.names().collect::<Vec<_>>().join(", ")
+            ),
+            e => e.into(),
+        })?;
+
+    Ok(UpstreamPool {
+        balancer: Balancer::new(
+            strategy,
+            SlowStart::new(
+                Duration::from_secs(config.slow_start_window_secs),
+                config.slow_start_aggression,
+                config.slow_start_min_weight_percent,
+            ),
+            servers
+                .iter()
+                .map(|server| server.url.to_string())
+                .collect(),
+        ),
+        health_check: Arc::new(HealthCheck::from_config(config)?),
+        path_rewrite: PathRewrite::new(config.strip_prefix.clone(), config.add_prefix.clone()),
+        timeouts: TimeoutTable::parse(
+            UpstreamTimeouts {
+                connect: timeout_from_millis(config.upstream_connect_timeout_ms),
+                per_try: timeout_from_millis(config.upstream_per_try_timeout_ms),
+                total: timeout_from_millis(config.upstream_total_timeout_ms),
+                idle: timeout_from_millis(config.upstream_idle_timeout_ms),
+            },
+            &config.upstream_route_timeouts,
+        )?,
+        name,
+        servers,
+    })
+}
+
+fn new_instance_id() -> String {
+    let started_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+
+    format!("{}-{}", std::process::id(), started_at)
+}
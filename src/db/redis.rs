@@ -1,10 +1,16 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use redis::{AsyncTypedCommands as _, cluster::ClusterClient, cluster_async::ClusterConnection};
+use reqwest::Url;
 
 use crate::{
+    algorithms::{LocationMappings, ServerResources},
     error::Error,
-    middleware::{ServerClient, StaticServerData},
+    middleware::StaticServerData,
+    services::ServerHealth,
 };
 
 #[derive(Clone)]
@@ -32,6 +38,12 @@ impl RedisClient {
             client
                 .update_server_weight(server.url.as_str(), server.weight)
                 .await?;
+
+            client.initialize_server_load(server.url.as_str()).await?;
+
+            client
+                .initialize_server_ready_time(server.url.as_str())
+                .await?;
         }
 
         Ok(client)
@@ -56,26 +68,58 @@ impl RedisClient {
 
     // Server Data Commands
 
-    /// Update the data of a server in Redis.
+    /// Update the data of a server in Redis, replacing any earlier entry for it.
     pub async fn update_server_url(&mut self, value: &str) -> Result<(), Error> {
+        self.0.lrem("server_urls", 0, value).await?;
         Ok(self.0.rpush("server_urls", value).await.map(|_| ())?)
     }
 
-    /// Get all server data from Redis.
-    pub async fn get_all_server_url(&mut self) -> Result<Vec<ServerClient>, Error> {
+    /// Get the URLs of all servers from Redis.
+    pub async fn get_all_server_url(&mut self) -> Result<Vec<Url>, Error> {
         self.0
             .lrange("server_urls", 0, -1)
             .await?
             .into_iter()
-            .map(|v| Ok(StaticServerData::from_json(v)?.into()))
+            .map(|v| v.parse().map_err(|_| Error::InvalidUrl))
             .collect::<Result<Vec<_>, _>>()
     }
 
     // Server Load Commands
 
-    /// Update the load of a server in Redis.
-    pub async fn update_server_load(&mut self, key: &str, value: u32) -> Result<(), Error> {
-        Ok(self.0.hset("server_load", key, value).await.map(|_| ())?)
+    /// Ensure a server has a load entry without changing an existing count.
+    pub async fn initialize_server_load(&mut self, key: &str) -> Result<(), Error> {
+        Ok(self.0.hincr("server_load", key, 0).await.map(|_| ())?)
+    }
+
+    /// Atomically change the in-flight load of a server.
+    ///
+    /// The change is mirrored into the instance's own load hash so it can be
+    /// reverted by [`RedisClient::release_instance_load`] if the instance dies. Both
+    /// hashes are updated and clamped at zero by a single script.
+    pub async fn increment_server_load(
+        &mut self,
+        instance_id: &str,
+        key: &str,
+        delta: i64,
+    ) -> Result<(), Error> {
+        let script = redis::Script::new(
+            r"
+            for _, load_key in ipairs(KEYS) do
+                if redis.call('HINCRBY', load_key, ARGV[1], ARGV[2]) < 0 then
+                    redis.call('HSET', load_key, ARGV[1], 0)
+                end
+            end
+            return 1
+            ",
+        );
+
+        Ok(script
+            .key("server_load")
+            .key(instance_load_key(instance_id))
+            .arg(key)
+            .arg(delta)
+            .invoke_async::<()>(&mut self.0)
+            .await?)
     }
 
     /// Get the load of a server from Redis.
@@ -84,7 +128,7 @@ impl RedisClient {
             .hget("server_load", key)
             .await
             .map_err(Error::RedisError)?
-            .map(|d| d.parse::<u32>().map_err(Error::ParseIntError))
+            .map(|d| parse_load(&d))
             .transpose()
     }
 
@@ -94,45 +138,360 @@ impl RedisClient {
             .hgetall("server_load")
             .await?
             .into_iter()
-            .map(|(k, v)| Ok((k, v.parse::<u32>().map_err(Error::ParseIntError)?)))
+            .map(|(k, v)| Ok((k, parse_load(&v)?)))
             .collect::<Result<HashMap<_, _>, _>>()
     }
 
-    // Latency Commands
+    /// Reset any server load that has drifted below zero.
+    pub async fn clamp_server_load(&mut self) -> Result<(), Error> {
+        let script = redis::Script::new(
+            r"
+            local loads = redis.call('HGETALL', KEYS[1])
+            for i = 1, #loads, 2 do
+                if tonumber(loads[i + 1]) < 0 then
+                    redis.call('HSET', KEYS[1], loads[i], 0)
+                end
+            end
+            return 1
+            ",
+        );
 
-    /// Update the mean latency record of a server in Redis.
-    pub async fn update_server_latency_record(
+        Ok(script
+            .key("server_load")
+            .invoke_async::<()>(&mut self.0)
+            .await?)
+    }
+
+    // Instance Commands
+
+    /// Register a balancer instance and mark it alive for `ttl_secs`.
+    pub async fn register_instance(
+        &mut self,
+        instance_id: &str,
+        ttl_secs: u64,
+    ) -> Result<(), Error> {
+        self.0.sadd("balancer_instances", instance_id).await?;
+        self.refresh_instance_heartbeat(instance_id, ttl_secs).await
+    }
+
+    /// Keep a balancer instance marked alive for another `ttl_secs`.
+    pub async fn refresh_instance_heartbeat(
+        &mut self,
+        instance_id: &str,
+        ttl_secs: u64,
+    ) -> Result<(), Error> {
+        Ok(self
+            .0
+            .set_ex(format!("balancer_heartbeat:{instance_id}"), 1, ttl_secs)
+            .await?)
+    }
+
+    /// Get the ids of all registered balancer instances.
+    pub async fn get_all_instances(&mut self) -> Result<Vec<String>, Error> {
+        Ok(self
+            .0
+            .smembers("balancer_instances")
+            .await?
+            .into_iter()
+            .collect())
+    }
+
+    /// Check whether a balancer instance heartbeat has not yet expired.
+    pub async fn is_instance_alive(&mut self, instance_id: &str) -> Result<bool, Error> {
+        Ok(self
+            .0
+            .exists(format!("balancer_heartbeat:{instance_id}"))
+            .await?)
+    }
+
+    /// Subtract a dead instance's in-flight load from the shared server load.
+    ///
+    /// Only the caller that unregisters the instance releases its load, so
+    /// concurrent reconcilers cannot subtract it twice.
+    pub async fn release_instance_load(&mut self, instance_id: &str) -> Result<bool, Error> {
+        if self.0.srem("balancer_instances", instance_id).await? == 0 {
+            return Ok(false);
+        }
+
+        let loads: HashMap<String, String> = self.0.hgetall(instance_load_key(instance_id)).await?;
+
+        for (key, load) in loads {
+            let load = load.parse::<i64>()?;
+            if load != 0 {
+                self.0.hincr("server_load", key, -load).await?;
+            }
+        }
+
+        self.0.del(instance_load_key(instance_id)).await?;
+
+        Ok(true)
+    }
+
+    // Health Commands
+
+    /// Replace the health of a server in Redis if it is still `expected`, `None` meaning
+    /// the server was never probed.
+    ///
+    /// The comparison and the write happen atomically, so a health updated meanwhile by
+    /// another balancer instance is never overwritten. Returns whether it was replaced.
+    pub async fn compare_and_set_server_health(
         &mut self,
         key: &str,
-        value: u128,
+        expected: Option<&ServerHealth>,
+        value: &ServerHealth,
+    ) -> Result<bool, Error> {
+        let script = redis::Script::new(
+            r"
+            local current = redis.call('HGET', KEYS[1], ARGV[1]) or ''
+            if current ~= ARGV[2] then
+                return 0
+            end
+            redis.call('HSET', KEYS[1], ARGV[1], ARGV[3])
+            return 1
+            ",
+        );
+
+        let expected = expected.map(serde_json::to_string).transpose()?;
+
+        Ok(script
+            .key("server_health")
+            .arg(key)
+            .arg(expected.unwrap_or_default())
+            .arg(serde_json::to_string(value)?)
+            .invoke_async::<bool>(&mut self.0)
+            .await?)
+    }
+
+    /// Get the health of a server from Redis.
+    pub async fn get_server_health(&mut self, key: &str) -> Result<Option<ServerHealth>, Error> {
+        self.0
+            .hget("server_health", key)
+            .await?
+            .map(|health| Ok(serde_json::from_str(&health)?))
+            .transpose()
+    }
+
+    /// Get the health of all probed servers from Redis.
+    pub async fn get_all_server_health(&mut self) -> Result<HashMap<String, ServerHealth>, Error> {
+        self.0
+            .hgetall("server_health")
+            .await?
+            .into_iter()
+            .map(|(k, v)| Ok((k, serde_json::from_str(&v)?)))
+            .collect::<Result<HashMap<_, _>, _>>()
+    }
+
+    /// Eject a server from rotation for `duration`.
+    ///
+    /// The server slow starts again once the ejection expires.
+    pub async fn eject_server(&mut self, key: &str, duration: Duration) -> Result<(), Error> {
+        let until = unix_millis() + duration.as_millis();
+
+        self.0
+            .hset("server_ready_at", key, until.to_string())
+            .await?;
+
+        Ok(self
+            .0
+            .hset("server_ejections", key, until.to_string())
+            .await
+            .map(|_| ())?)
+    }
+
+    /// Get the servers whose ejection has not yet expired.
+    pub async fn get_ejected_servers(&mut self) -> Result<HashSet<String>, Error> {
+        let now = unix_millis();
+
+        Ok(self
+            .0
+            .hgetall("server_ejections")
+            .await?
+            .into_iter()
+            .filter(|(_, until)| until.parse::<u128>().is_ok_and(|until| until > now))
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    // Slow Start Commands
+
+    /// Record when a server joined the pool, unless it is already known.
+    pub async fn initialize_server_ready_time(&mut self, key: &str) -> Result<(), Error> {
+        Ok(self
+            .0
+            .hset_nx("server_ready_at", key, unix_millis().to_string())
+            .await
+            .map(|_| ())?)
+    }
+
+    /// Record that a server came back into rotation now.
+    pub async fn mark_server_ready(&mut self, key: &str) -> Result<(), Error> {
+        Ok(self
+            .0
+            .hset("server_ready_at", key, unix_millis().to_string())
+            .await
+            .map(|_| ())?)
+    }
+
+    /// Get the time, in Unix milliseconds, each server last entered rotation.
+    pub async fn get_all_server_ready_times(&mut self) -> Result<HashMap<String, u128>, Error> {
+        Ok(self
+            .0
+            .hgetall("server_ready_at")
+            .await?
+            .into_iter()
+            .filter_map(|(key, at)| Some((key, at.parse().ok()?)))
+            .collect())
+    }
+
+    // Resource Commands
+
+    /// Update the resource utilisation last reported by a server.
+    pub async fn update_server_resources(
+        &mut self,
+        key: &str,
+        value: &ServerResources,
     ) -> Result<(), Error> {
-        Ok(self.0.rpush(key, value).await.map(|_| ())?)
+        Ok(self
+            .0
+            .hset("server_resources", key, serde_json::to_string(value)?)
+            .await
+            .map(|_| ())?)
+    }
+
+    /// Forget the resource utilisation of a server that stopped reporting it.
+    pub async fn remove_server_resources(&mut self, key: &str) -> Result<(), Error> {
+        Ok(self.0.hdel("server_resources", key).await.map(|_| ())?)
     }
 
-    pub async fn get_server_latency_record(&mut self, key: &str) -> Result<Vec<u128>, Error> {
+    /// Get the resource utilisation of all servers that reported it.
+    pub async fn get_all_server_resources(
+        &mut self,
+    ) -> Result<HashMap<String, ServerResources>, Error> {
         self.0
-            .lrange(key, 0, -1)
+            .hgetall("server_resources")
             .await?
             .into_iter()
-            .map(|v| Ok(v.parse()?))
-            .collect::<Result<Vec<_>, _>>()
+            .map(|(k, v)| Ok((k, serde_json::from_str(&v)?)))
+            .collect::<Result<HashMap<_, _>, _>>()
+    }
+
+    // Location Commands
+
+    /// Store region to pool mappings and fallbacks, replacing existing regions of the same name.
+    pub async fn update_location_mappings(
+        &mut self,
+        mappings: &LocationMappings,
+    ) -> Result<(), Error> {
+        for (region, pool) in &mappings.pools {
+            self.0
+                .hset("location_pools", region, serde_json::to_string(pool)?)
+                .await?;
+        }
+
+        for (region, fallback) in &mappings.fallbacks {
+            self.0.hset("location_fallbacks", region, fallback).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the servers mapped to a region.
+    pub async fn get_location_pool(&mut self, region: &str) -> Result<Option<Vec<String>>, Error> {
+        self.0
+            .hget("location_pools", region)
+            .await?
+            .map(|pool| Ok(serde_json::from_str(&pool)?))
+            .transpose()
+    }
+
+    /// Get the region consulted when a region has no available servers.
+    pub async fn get_location_fallback(&mut self, region: &str) -> Result<Option<String>, Error> {
+        Ok(self.0.hget("location_fallbacks", region).await?)
+    }
+
+    // Latency Commands
+
+    /// Record a latency sample for a server, keeping only the most recent `window_size`.
+    pub async fn update_server_latency_record(
+        &mut self,
+        key: &str,
+        value: u128,
+        window_size: usize,
+    ) -> Result<(), Error> {
+        let record_key = latency_record_key(key);
+        let stop = isize::try_from(window_size).unwrap_or(isize::MAX) - 1;
+
+        self.0
+            .lpush(&record_key, format!("{}:{}", unix_millis(), value))
+            .await?;
+        Ok(self.0.ltrim(&record_key, 0, stop).await?)
+    }
+
+    /// Get the latency samples of a server recorded within `max_age`, newest first.
+    pub async fn get_server_latency_record(
+        &mut self,
+        key: &str,
+        max_age: Option<Duration>,
+    ) -> Result<Vec<u128>, Error> {
+        let oldest = max_age.map(|age| unix_millis().saturating_sub(age.as_millis()));
+        let samples = self.0.lrange(latency_record_key(key), 0, -1).await?;
+
+        Ok(recent_latency_samples(samples, oldest))
     }
 
     /// Get the latency record of all servers in Redis.
     pub async fn get_servers_latency_record(
         &mut self,
+        max_age: Option<Duration>,
     ) -> Result<HashMap<String, Vec<u128>>, Error> {
-        let server_client = self.get_all_server_url().await?;
+        let urls = self.get_all_server_url().await?;
 
         let mut res: HashMap<String, Vec<u128>> = HashMap::new();
 
-        for server in server_client {
-            let latencies = self.get_server_latency_record(server.url.as_str()).await?;
-            res.insert(server.url.to_string(), latencies);
+        for url in urls {
+            let latencies = self
+                .get_server_latency_record(url.as_str(), max_age)
+                .await?;
+            res.insert(url.to_string(), latencies);
         }
         Ok(res)
     }
 
+    /// Fold a latency sample into a server's exponentially weighted moving average.
+    ///
+    /// The average is updated atomically and its rounded value is published as the
+    /// server's mean latency.
+    pub async fn update_server_latency_ewma(
+        &mut self,
+        key: &str,
+        value: u128,
+        alpha: f64,
+    ) -> Result<(), Error> {
+        let script = redis::Script::new(
+            r"
+            local previous = tonumber(redis.call('HGET', KEYS[1], ARGV[1]))
+            local sample = tonumber(ARGV[2])
+            local alpha = tonumber(ARGV[3])
+            local ewma = sample
+            if previous then
+                ewma = alpha * sample + (1 - alpha) * previous
+            end
+            redis.call('HSET', KEYS[1], ARGV[1], tostring(ewma))
+            redis.call('HSET', KEYS[2], ARGV[1], math.floor(ewma + 0.5))
+            return 1
+            ",
+        );
+
+        Ok(script
+            .key("{server_latency}_ewma")
+            .key("server_latency")
+            .arg(key)
+            .arg(value.to_string())
+            .arg(alpha)
+            .invoke_async::<()>(&mut self.0)
+            .await?)
+    }
+
     /// Update the mean latency of a server in Redis.
     pub async fn update_server_mean_latency(
         &mut self,
@@ -146,6 +505,11 @@ impl RedisClient {
             .map(|_| ())?)
     }
 
+    /// Stop tracking the mean latency of a server, which has no recent samples.
+    pub async fn remove_server_mean_latency(&mut self, key: &str) -> Result<(), Error> {
+        Ok(self.0.hdel("server_latency", key).await.map(|_| ())?)
+    }
+
     /// Get the mean latency of all servers in Redis.
     pub async fn get_all_server_mean_latency(&mut self) -> Result<HashMap<String, u32>, Error> {
         self.0
@@ -176,4 +540,157 @@ impl RedisClient {
             .map(|(k, v)| Ok((k, v.parse::<u32>().map_err(Error::ParseIntError)?)))
             .collect::<Result<HashMap<_, _>, _>>()
     }
+
+    // Round Robin Commands
+
+    /// Advance the round-robin counter of a pool, shared by all balancer instances.
+    pub async fn next_round_robin_counter(&mut self, pool: &str) -> Result<u64, Error> {
+        let counter: isize = self.0.incr(round_robin_counter_key(pool), 1).await?;
+        Ok(counter.unsigned_abs() as u64)
+    }
+
+    /// Pick the next server of a smooth weighted round-robin, as done by nginx.
+    ///
+    /// Each server's current weight grows by its weight, the server with the highest
+    /// current weight is picked and lowered by the sum of the weights. The current
+    /// weights are updated atomically so every instance shares one rotation per pool.
+    pub async fn next_smooth_weighted_server(
+        &mut self,
+        pool: &str,
+        servers: &[(String, u32)],
+    ) -> Result<Option<String>, Error> {
+        let script = redis::Script::new(
+            r"
+            local best, best_weight = nil, nil
+            local total = 0
+            for i = 1, #ARGV, 2 do
+                local weight = tonumber(ARGV[i + 1])
+                local current = redis.call('HINCRBY', KEYS[1], ARGV[i], weight)
+                total = total + weight
+                if best == nil or current > best_weight then
+                    best, best_weight = ARGV[i], current
+                end
+            end
+            if best then
+                redis.call('HINCRBY', KEYS[1], best, -total)
+            end
+            return best
+            ",
+        );
+
+        let mut invocation = script.key(smooth_weighted_round_robin_key(pool));
+        for (url, weight) in servers {
+            invocation.arg(url).arg(weight);
+        }
+
+        Ok(invocation.invoke_async(&mut self.0).await?)
+    }
+}
+
+/// Key of a pool's round-robin counter
+fn round_robin_counter_key(pool: &str) -> String {
+    format!("round_robin_counter:{{{pool}}}")
+}
+
+/// Key of the hash holding a pool's smooth weighted round-robin current weights
+fn smooth_weighted_round_robin_key(pool: &str) -> String {
+    format!("smooth_weighted_round_robin:{{{pool}}}")
+}
+
+/// Load hash of a balancer instance, hash tagged into the slot of `server_load` so
+/// both can be updated by one script on a cluster.
+fn instance_load_key(instance_id: &str) -> String {
+    format!("{{server_load}}:{instance_id}")
+}
+
+/// Parse a load count, treating transiently negative values as zero.
+fn parse_load(load: &str) -> Result<u32, Error> {
+    Ok(u32::try_from(load.parse::<i64>()?.max(0)).unwrap_or(u32::MAX))
+}
+
+fn latency_record_key(server_url: &str) -> String {
+    format!("server_latency_record:{server_url}")
+}
+
+/// Latencies of the `recorded_at:latency` samples, newest first, recorded at or after
+/// `oldest` Unix milliseconds.
+fn recent_latency_samples(samples: Vec<String>, oldest: Option<u128>) -> Vec<u128> {
+    samples
+        .into_iter()
+        .filter_map(|sample| {
+            let (recorded_at, latency) = sample.split_once(':')?;
+            Some((recorded_at.parse::<u128>().ok()?, latency.parse().ok()?))
+        })
+        .take_while(|(recorded_at, _)| oldest.is_none_or(|oldest| *recorded_at >= oldest))
+        .map(|(_, latency)| latency)
+        .collect()
+}
+
+fn unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_samples_within_the_window() {
+        let samples = vec![
+            "3000:30".to_string(),
+            "2000:20".to_string(),
+            "1000:10".to_string(),
+        ];
+
+        assert_eq!(
+            recent_latency_samples(samples.clone(), Some(2000)),
+            vec![30, 20]
+        );
+        assert_eq!(
+            recent_latency_samples(samples.clone(), None),
+            vec![30, 20, 10]
+        );
+        assert!(recent_latency_samples(samples, Some(5000)).is_empty());
+    }
+
+    #[test]
+    fn instance_load_shares_the_server_load_slot() {
+        let key = instance_load_key("instance-1");
+        let tag = key
+            .split_once('{')
+            .and_then(|(_, rest)| rest.split_once('}'))
+            .map(|(tag, _)| tag);
+
+        // Redis hashes a key with a `{tag}` by the tag alone
+        assert_eq!(tag, Some("server_load"));
+        assert_ne!(key, instance_load_key("instance-2"));
+    }
+
+    #[test]
+    fn keeps_round_robin_state_per_pool() {
+        assert_ne!(
+            round_robin_counter_key("api"),
+            round_robin_counter_key("static")
+        );
+        assert_ne!(
+            smooth_weighted_round_robin_key("api"),
+            smooth_weighted_round_robin_key("static")
+        );
+
+        // Requests alternating between two pools of two servers each still rotate
+        // through both servers of every pool
+        let mut counters = HashMap::<String, u64>::new();
+        let mut picked = HashMap::<&str, HashSet<u64>>::new();
+        for pool in ["api", "static"].repeat(4) {
+            let counter = counters.entry(round_robin_counter_key(pool)).or_default();
+            *counter += 1;
+            picked.entry(pool).or_default().insert(*counter % 2);
+        }
+
+        assert_eq!(picked["api"].len(), 2);
+        assert_eq!(picked["static"].len(), 2);
+    }
 }
@@ -1,7 +1,16 @@
-use std::string::ParseError;
+use std::{string::ParseError, time::Duration};
 
-use axum::response::{IntoResponse, Response};
+use axum::{
+    http::{
+        HeaderValue,
+        header::{CONTENT_TYPE, RETRY_AFTER},
+    },
+    response::{IntoResponse, Response},
+};
 use reqwest::StatusCode;
+use serde::Serialize;
+
+use crate::middleware::TimeoutKind;
 
 /// Custom error types for the load balancer application
 #[derive(Debug, thiserror::Error)]
@@ -14,6 +23,10 @@ pub enum Error {
     Unauthorized,
     #[error("Other: {0}")]
     Other(#[from] anyhow::Error),
+    #[error("Upstream Error: {0}")]
+    Upstream(reqwest::Error),
+    #[error("Gateway Timeout: {0} timeout exceeded")]
+    UpstreamTimeout(TimeoutKind),
     #[error("Redis Error: {0}")]
     RedisError(#[from] redis::RedisError),
     #[error("Method Not Allowed")]
@@ -24,6 +37,10 @@ pub enum Error {
     InvalidResponse,
     #[error("No Server Available")]
     NoServerAvailable,
+    #[error("Unknown Location: {0}")]
+    UnknownLocation(String),
+    #[error("Unknown Strategy: {0}")]
+    UnknownStrategy(String),
     #[error("Parse Error")]
     ParseIntError(#[from] std::num::ParseIntError),
     #[error("Parse Error")]
@@ -32,24 +49,158 @@ pub enum Error {
     SerializationError(#[from] serde_json::Error),
 }
 
-impl IntoResponse for Error {
-    fn into_response(self) -> Response {
+impl Error {
+    /// Status code the error is reported to the client with
+    pub fn status(&self) -> StatusCode {
         match self {
-            Error::NotFound => (StatusCode::NOT_FOUND, self).into_response(),
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Error::InvalidUrl | Error::UnknownLocation(_) => StatusCode::BAD_REQUEST,
+            Error::NoServerAvailable => StatusCode::SERVICE_UNAVAILABLE,
+            Error::UpstreamTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::Upstream(e) if e.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            Error::Upstream(_) | Error::InvalidResponse => StatusCode::BAD_GATEWAY,
             Error::InternalServerError
             | Error::Other(_)
-            | Error::InvalidResponse
+            | Error::UnknownStrategy(_)
             | Error::RedisError(_)
-            | Error::NoServerAvailable
             | Error::ParseIntError(_)
             | Error::ParseError(_)
-            | Error::SerializationError(_) => {
-                // TODO: Log error
-                (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+            | Error::SerializationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Description safe to show to clients, without backend addresses or internal errors
+    fn detail(&self) -> String {
+        match self {
+            Error::UnknownLocation(_) => self.to_string(),
+            Error::NoServerAvailable => "No healthy backend is available".to_string(),
+            Error::UpstreamTimeout(kind) => {
+                format!("The backend did not respond within the {kind} timeout")
+            }
+            Error::Upstream(e) if e.is_timeout() => {
+                "The backend did not respond in time".to_string()
+            }
+            Error::Upstream(e) if e.is_connect() => "Could not connect to the backend".to_string(),
+            Error::Upstream(_) => "The connection to the backend failed".to_string(),
+            Error::InvalidResponse => "The backend sent an invalid response".to_string(),
+            _ => self
+                .status()
+                .canonical_reason()
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+}
+
+/// Body format of error responses
+#[derive(Clone, Copy, Default)]
+pub enum ErrorFormat {
+    /// `application/problem+json` as described in RFC 9457
+    #[default]
+    ProblemJson,
+    PlainText,
+}
+
+impl ErrorFormat {
+    pub fn parse(format: &str) -> anyhow::Result<Self> {
+        match format {
+            "problem_json" | "json" => Ok(ErrorFormat::ProblemJson),
+            "plain_text" | "text" => Ok(ErrorFormat::PlainText),
+            other => {
+                anyhow::bail!("Unknown error format '{other}', expected problem_json or plain_text")
+            }
+        }
+    }
+}
+
+/// RFC 9457 problem details
+#[derive(Serialize)]
+struct ProblemDetails<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'a str,
+    status: u16,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+/// Turns errors into logged client responses
+#[derive(Clone, Copy)]
+pub struct ErrorResponder {
+    pub format: ErrorFormat,
+    /// Sent as `Retry-After` when no backend is available
+    pub retry_after: Duration,
+}
+
+impl Default for ErrorResponder {
+    fn default() -> Self {
+        Self {
+            format: ErrorFormat::default(),
+            retry_after: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ErrorResponder {
+    pub fn respond(&self, error: &Error, request_id: Option<&str>) -> Response {
+        let status = error.status();
+        let request_id_for_log = request_id.unwrap_or("-");
+
+        if status.is_server_error() {
+            tracing::error!(
+                "Request {} failed with {}: {}",
+                request_id_for_log,
+                status,
+                error
+            );
+        } else {
+            tracing::warn!(
+                "Request {} rejected with {}: {}",
+                request_id_for_log,
+                status,
+                error
+            );
+        }
+
+        let title = status.canonical_reason().unwrap_or_default();
+
+        let mut response = match self.format {
+            ErrorFormat::ProblemJson => {
+                let problem = ProblemDetails {
+                    kind: "about:blank",
+                    title,
+                    status: status.as_u16(),
+                    detail: error.detail(),
+                    request_id,
+                };
+                let body = serde_json::to_string(&problem).unwrap_or_default();
+
+                let mut response = (status, body).into_response();
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("application/problem+json"),
+                );
+                response
             }
-            Error::Unauthorized => (StatusCode::UNAUTHORIZED, self).into_response(),
-            Error::MethodNotAllowed => (StatusCode::METHOD_NOT_ALLOWED, self).into_response(),
-            Error::InvalidUrl => (StatusCode::BAD_REQUEST, self).into_response(),
+            ErrorFormat::PlainText => (status, error.detail()).into_response(),
+        };
+
+        if matches!(error, Error::NoServerAvailable) {
+            response.headers_mut().insert(
+                RETRY_AFTER,
+                HeaderValue::from(self.retry_after.as_secs().max(1)),
+            );
         }
+
+        response
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        ErrorResponder::default().respond(&self, None)
     }
 }
@@ -0,0 +1,16 @@
+#![deny(clippy::disallowed_methods)]
+
+//! Redis-backed HTTP load balancer.
+//!
+//! The binary runs it with the built-in strategies; other crates can register their
+//! own [`algorithms::LoadBalancingStrategy`] in a [`algorithms::StrategyRegistry`]
+//! and pass it to [`config::State::new`].
+
+pub mod algorithms;
+pub mod app;
+pub mod config;
+pub mod db;
+pub mod error;
+pub mod middleware;
+mod route;
+pub mod services;
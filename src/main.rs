@@ -4,20 +4,12 @@ use std::{net::SocketAddr, str::FromStr as _};
 
 use tracing::Level;
 
-use crate::{
+use load_balancer::{
+    algorithms::StrategyRegistry,
     app::App,
     config::{State, SystemConfig},
 };
 
-pub mod algorithms;
-mod app;
-pub mod config;
-pub mod db;
-pub mod error;
-mod middleware;
-mod route;
-mod services;
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = SystemConfig::from_env()?;
@@ -27,7 +19,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .pretty()
         .init();
 
-    let state = State::new(&config).await?;
+    let state = State::new(&config, StrategyRegistry::default()).await?;
 
     let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
     tracing::info!("Listening on: {}", addr);
@@ -0,0 +1,340 @@
+use std::{
+    str::FromStr as _,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::{
+    HeaderMap, HeaderName, HeaderValue,
+    header::{COOKIE, SET_COOKIE},
+};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac as _};
+use sha2::Sha256;
+
+use crate::config::SystemConfig;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Where the affinity token travels between the client and the balancer
+pub enum AffinityMode {
+    /// Set with `Set-Cookie` and read back from `Cookie`
+    Cookie(String),
+    /// Returned in a response header the client echoes in its requests
+    Header(HeaderName),
+}
+
+/// Affinity token of a request: an opaque backend ID and its expiry
+pub struct AffinityToken {
+    backend_id: String,
+    expires_at: u64,
+}
+
+/// Session affinity through signed tokens naming a backend.
+///
+/// Tokens carry a keyed hash of the backend URL rather than the URL itself, so
+/// internal addresses are not exposed, and are signed so clients cannot forge them.
+pub struct SessionAffinity {
+    mode: AffinityMode,
+    ttl: Duration,
+    /// HMAC keyed with the signing key, cloned for every signature
+    mac: HmacSha256,
+}
+
+impl SessionAffinity {
+    pub fn from_config(config: &SystemConfig) -> anyhow::Result<Option<Self>> {
+        let mode = match config.session_affinity.as_str() {
+            "" | "none" => return Ok(None),
+            "cookie" => AffinityMode::Cookie(config.session_affinity_cookie.clone()),
+            "header" => {
+                AffinityMode::Header(HeaderName::from_str(&config.session_affinity_header)?)
+            }
+            other => {
+                anyhow::bail!("Unknown session affinity '{other}', expected none, cookie or header")
+            }
+        };
+
+        let key = config
+            .session_affinity_key
+            .clone()
+            .filter(|key| !key.is_empty())
+            .ok_or_else(|| {
+                anyhow::anyhow!("SESSION_AFFINITY_KEY is required for session affinity")
+            })?;
+
+        Ok(Some(Self {
+            mode,
+            ttl: Duration::from_secs(config.session_affinity_ttl_secs.max(1)),
+            mac: HmacSha256::new_from_slice(key.as_bytes())
+                .map_err(|e| anyhow::anyhow!("Invalid SESSION_AFFINITY_KEY: {e}"))?,
+        }))
+    }
+
+    /// Valid, unexpired token sent with the request
+    pub fn read(&self, headers: &HeaderMap) -> Option<AffinityToken> {
+        let token = match &self.mode {
+            AffinityMode::Cookie(name) => headers
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(cookie, _)| cookie == name)
+                .map(|(_, value)| value.to_string())?,
+            AffinityMode::Header(name) => headers.get(name)?.to_str().ok()?.to_string(),
+        };
+
+        let (payload, signature) = token.rsplit_once('.')?;
+        let (backend_id, expires_at) = payload.split_once('.')?;
+
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.mac(payload).verify_slice(&signature).ok()?;
+
+        let expires_at = expires_at.parse().ok()?;
+        if expires_at <= unix_secs() {
+            return None;
+        }
+
+        Some(AffinityToken {
+            backend_id: backend_id.to_string(),
+            expires_at,
+        })
+    }
+
+    /// Server the token points to among `servers`
+    pub fn resolve<'a>(
+        &self,
+        token: &AffinityToken,
+        mut servers: impl Iterator<Item = &'a String>,
+    ) -> Option<&'a String> {
+        servers.find(|url| self.backend_id(url) == token.backend_id)
+    }
+
+    /// Whether the client needs a new token, because it has none, it points to another
+    /// server or it is past half its lifetime
+    pub fn needs_update(&self, token: Option<&AffinityToken>, url: &str) -> bool {
+        token.is_none_or(|token| {
+            token.backend_id != self.backend_id(url)
+                || token.expires_at < unix_secs() + self.ttl.as_secs() / 2
+        })
+    }
+
+    /// Adds a token for `url` to the response headers
+    pub fn write(&self, headers: &mut HeaderMap, url: &str) {
+        let payload = format!(
+            "{}.{}",
+            self.backend_id(url),
+            unix_secs() + self.ttl.as_secs()
+        );
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes());
+        let token = format!("{payload}.{signature}");
+
+        match &self.mode {
+            AffinityMode::Cookie(name) => {
+                let cookie = format!(
+                    "{name}={token}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+                    self.ttl.as_secs()
+                );
+                if let Ok(value) = HeaderValue::from_str(&cookie) {
+                    headers.append(SET_COOKIE, value);
+                }
+            }
+            AffinityMode::Header(name) => {
+                if let Ok(value) = HeaderValue::from_str(&token) {
+                    headers.insert(name.clone(), value);
+                }
+            }
+        }
+    }
+
+    /// Opaque, keyed ID of a backend
+    fn backend_id(&self, url: &str) -> String {
+        let digest = self.mac(&format!("backend|{url}")).finalize().into_bytes();
+        URL_SAFE_NO_PAD.encode(&digest[..12])
+    }
+
+    fn mac(&self, data: &str) -> HmacSha256 {
+        let mut mac = self.mac.clone();
+        mac.update(data.as_bytes());
+        mac
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: &str = "http://10.0.0.1:3001/";
+    const OTHER_SERVER: &str = "http://10.0.0.2:3002/";
+
+    fn session_affinity(mode: AffinityMode, key: &str) -> SessionAffinity {
+        SessionAffinity {
+            mode,
+            ttl: Duration::from_secs(3600),
+            mac: HmacSha256::new_from_slice(key.as_bytes()).expect("hmac key"),
+        }
+    }
+
+    fn cookie_affinity() -> SessionAffinity {
+        session_affinity(AffinityMode::Cookie("lb".to_string()), "secret")
+    }
+
+    /// Token `write` hands to the client for `url`
+    fn issue(affinity: &SessionAffinity, url: &str) -> String {
+        let mut headers = HeaderMap::new();
+        affinity.write(&mut headers, url);
+
+        let cookie = headers
+            .get(SET_COOKIE)
+            .and_then(|value| value.to_str().ok())
+            .expect("set-cookie");
+        let (pair, _) = cookie.split_once(';').expect("cookie attributes");
+        let (_, token) = pair.split_once('=').expect("cookie value");
+
+        token.to_string()
+    }
+
+    /// Token for `url` expiring at `expires_at`, signed by `affinity`
+    fn sign(affinity: &SessionAffinity, url: &str, expires_at: u64) -> String {
+        let payload = format!("{}.{}", affinity.backend_id(url), expires_at);
+        let signature = URL_SAFE_NO_PAD.encode(affinity.mac(&payload).finalize().into_bytes());
+
+        format!("{payload}.{signature}")
+    }
+
+    fn with_cookie(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_str(&format!("theme=dark; lb={token}; other=1"))
+                .expect("cookie header"),
+        );
+        headers
+    }
+
+    fn servers() -> Vec<String> {
+        vec![SERVER.to_string(), OTHER_SERVER.to_string()]
+    }
+
+    #[test]
+    fn reads_back_a_valid_cookie() {
+        let affinity = cookie_affinity();
+        let token = affinity
+            .read(&with_cookie(&issue(&affinity, SERVER)))
+            .expect("valid token");
+        let servers = servers();
+
+        assert_eq!(
+            affinity.resolve(&token, servers.iter()).map(String::as_str),
+            Some(SERVER)
+        );
+        assert!(!affinity.needs_update(Some(&token), SERVER));
+    }
+
+    #[test]
+    fn reads_back_a_valid_header() {
+        let affinity = session_affinity(
+            AffinityMode::Header(HeaderName::from_static("x-affinity")),
+            "secret",
+        );
+        let mut headers = HeaderMap::new();
+        affinity.write(&mut headers, SERVER);
+
+        let token = affinity.read(&headers).expect("valid token");
+        let servers = servers();
+
+        assert_eq!(
+            affinity.resolve(&token, servers.iter()).map(String::as_str),
+            Some(SERVER)
+        );
+    }
+
+    #[test]
+    fn ignores_requests_without_a_token() {
+        let affinity = cookie_affinity();
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("theme=dark"));
+
+        assert!(affinity.read(&headers).is_none());
+        assert!(affinity.needs_update(None, SERVER));
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let affinity = cookie_affinity();
+        let token = sign(&affinity, SERVER, unix_secs() - 1);
+
+        assert!(affinity.read(&with_cookie(&token)).is_none());
+    }
+
+    #[test]
+    fn rejects_tokens_signed_with_another_key() {
+        let affinity = cookie_affinity();
+        let forger = session_affinity(AffinityMode::Cookie("lb".to_string()), "guessed");
+        let token = sign(&forger, SERVER, unix_secs() + 3600);
+
+        assert!(affinity.read(&with_cookie(&token)).is_none());
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let affinity = cookie_affinity();
+        let token = issue(&affinity, SERVER);
+        let (payload, signature) = token.rsplit_once('.').expect("signed token");
+        let (backend_id, expires_at) = payload.split_once('.').expect("payload");
+
+        // Extending the expiry or pointing the token at another server breaks the signature
+        let extended = format!("{backend_id}.{}.{signature}", expires_at.to_owned() + "0");
+        let redirected = format!(
+            "{}.{expires_at}.{signature}",
+            affinity.backend_id(OTHER_SERVER)
+        );
+
+        for forged in [extended, redirected, "garbage".to_string(), String::new()] {
+            assert!(affinity.read(&with_cookie(&forged)).is_none(), "{forged}");
+        }
+    }
+
+    #[test]
+    fn updates_tokens_pointing_to_another_server() {
+        let affinity = cookie_affinity();
+        let token = affinity
+            .read(&with_cookie(&issue(&affinity, SERVER)))
+            .expect("valid token");
+
+        assert!(affinity.needs_update(Some(&token), OTHER_SERVER));
+    }
+
+    #[test]
+    fn does_not_resolve_servers_out_of_rotation() {
+        let affinity = cookie_affinity();
+        let token = affinity
+            .read(&with_cookie(&issue(&affinity, SERVER)))
+            .expect("valid token");
+        let remaining = [OTHER_SERVER.to_string()];
+
+        assert!(affinity.resolve(&token, remaining.iter()).is_none());
+    }
+
+    #[test]
+    fn keeps_backend_urls_out_of_tokens() {
+        let affinity = cookie_affinity();
+        let token = issue(&affinity, SERVER);
+
+        assert!(!token.contains(SERVER));
+        assert!(!token.contains("10.0.0.1"));
+
+        let decoded = token
+            .split('.')
+            .filter_map(|part| URL_SAFE_NO_PAD.decode(part).ok())
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+            .collect::<Vec<_>>();
+        assert!(decoded.iter().all(|part| !part.contains("10.0.0.1")));
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Circuit breaker settings shared by every backend
+#[derive(Clone, Copy)]
+pub struct CircuitBreakerConfig {
+    /// Number of most recent calls used to compute the failure and slow call rates
+    pub window_size: usize,
+    /// Calls needed in the window before the rates are evaluated
+    pub minimum_calls: usize,
+    /// Failure rate, in percent, at which the circuit opens
+    pub failure_rate_threshold: f64,
+    /// Slow call rate, in percent, at which the circuit opens
+    pub slow_call_rate_threshold: f64,
+    /// Calls taking at least this long are counted as slow
+    pub slow_call_duration: Duration,
+    /// Time the circuit stays open before letting probe calls through
+    pub open_duration: Duration,
+    /// Number of probe calls allowed while half-open
+    pub half_open_max_calls: usize,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Calls flow normally
+    #[default]
+    Closed,
+    /// Calls are sent to other backends
+    Open,
+    /// A limited number of probe calls decide whether to close or reopen the circuit
+    HalfOpen,
+}
+
+#[derive(Clone, Copy)]
+struct CallResult {
+    failed: bool,
+    slow: bool,
+}
+
+#[derive(Default)]
+struct Breaker {
+    state: CircuitState,
+    opened_at: Option<Instant>,
+    calls: VecDeque<CallResult>,
+    half_open_in_flight: usize,
+}
+
+impl Breaker {
+    /// Failure and slow call rates of the window, in percent
+    fn rates(&self) -> (f64, f64) {
+        let total = self.calls.len().max(1) as f64;
+        let failed = self.calls.iter().filter(|c| c.failed).count() as f64;
+        let slow = self.calls.iter().filter(|c| c.slow).count() as f64;
+
+        (failed * 100.0 / total, slow * 100.0 / total)
+    }
+
+    fn transition(&mut self, url: &str, state: CircuitState) {
+        tracing::warn!(
+            "Circuit breaker for {} is now {:?} (was {:?})",
+            url,
+            state,
+            self.state
+        );
+
+        self.state = state;
+        self.calls.clear();
+        self.half_open_in_flight = 0;
+        self.opened_at = (state == CircuitState::Open).then(Instant::now);
+    }
+}
+
+/// State of a backend's circuit breaker, as exposed by the admin API
+#[derive(Serialize)]
+pub struct CircuitBreakerStatus {
+    pub url: String,
+    pub state: CircuitState,
+    pub failure_rate: f64,
+    pub slow_call_rate: f64,
+    pub calls: usize,
+}
+
+/// Per-backend circuit breakers of this balancer instance
+pub struct CircuitBreakers {
+    config: CircuitBreakerConfig,
+    breakers: Mutex<HashMap<String, Breaker>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Servers whose circuit currently rejects calls
+    pub fn rejected_servers(&self) -> HashSet<String> {
+        let Ok(breakers) = self.breakers.lock() else {
+            return HashSet::new();
+        };
+
+        breakers
+            .iter()
+            .filter(|(_, breaker)| !self.permits(breaker))
+            .map(|(url, _)| url.clone())
+            .collect()
+    }
+
+    /// Asks the circuit of a server for permission to send it a call
+    pub fn try_acquire(self: &Arc<Self>, url: &str) -> Option<CircuitPermit> {
+        let mut breakers = self.breakers.lock().ok()?;
+        let breaker = breakers.entry(url.to_string()).or_default();
+
+        if !self.permits(breaker) {
+            return None;
+        }
+
+        if breaker.state == CircuitState::Open {
+            breaker.transition(url, CircuitState::HalfOpen);
+        }
+
+        let half_open = breaker.state == CircuitState::HalfOpen;
+        if half_open {
+            breaker.half_open_in_flight += 1;
+        }
+
+        Some(CircuitPermit {
+            breakers: self.clone(),
+            url: url.to_string(),
+            half_open,
+            recorded: false,
+        })
+    }
+
+    pub fn statuses(&self) -> Vec<CircuitBreakerStatus> {
+        let Ok(breakers) = self.breakers.lock() else {
+            return Vec::new();
+        };
+
+        breakers
+            .iter()
+            .map(|(url, breaker)| {
+                let (failure_rate, slow_call_rate) = breaker.rates();
+                CircuitBreakerStatus {
+                    url: url.clone(),
+                    state: breaker.state,
+                    failure_rate,
+                    slow_call_rate,
+                    calls: breaker.calls.len(),
+                }
+            })
+            .collect()
+    }
+
+    fn permits(&self, breaker: &Breaker) -> bool {
+        match breaker.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => breaker
+                .opened_at
+                .is_none_or(|opened_at| opened_at.elapsed() >= self.config.open_duration),
+            CircuitState::HalfOpen => {
+                breaker.calls.len() + breaker.half_open_in_flight < self.config.half_open_max_calls
+            }
+        }
+    }
+
+    fn record(&self, url: &str, half_open: bool, result: Option<CallResult>) {
+        let Ok(mut breakers) = self.breakers.lock() else {
+            return;
+        };
+        let Some(breaker) = breakers.get_mut(url) else {
+            return;
+        };
+
+        // Calls started before the circuit changed state no longer count
+        let current = if half_open {
+            CircuitState::HalfOpen
+        } else {
+            CircuitState::Closed
+        };
+        if breaker.state != current {
+            return;
+        }
+
+        if half_open {
+            breaker.half_open_in_flight = breaker.half_open_in_flight.saturating_sub(1);
+        }
+
+        let Some(result) = result else {
+            return;
+        };
+
+        breaker.calls.push_back(result);
+        while breaker.calls.len() > self.config.window_size {
+            breaker.calls.pop_front();
+        }
+
+        let (failure_rate, slow_call_rate) = breaker.rates();
+        let tripped = failure_rate >= self.config.failure_rate_threshold
+            || slow_call_rate >= self.config.slow_call_rate_threshold;
+
+        match breaker.state {
+            CircuitState::Closed if breaker.calls.len() >= self.config.minimum_calls && tripped => {
+                breaker.transition(url, CircuitState::Open);
+            }
+            CircuitState::HalfOpen if breaker.calls.len() >= self.config.half_open_max_calls => {
+                let state = if tripped {
+                    CircuitState::Open
+                } else {
+                    CircuitState::Closed
+                };
+                breaker.transition(url, state);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Permission to send one call to a server, released when dropped
+pub struct CircuitPermit {
+    breakers: Arc<CircuitBreakers>,
+    url: String,
+    half_open: bool,
+    recorded: bool,
+}
+
+impl CircuitPermit {
+    /// Records the result of the call
+    pub fn record(mut self, failed: bool, duration: Duration) {
+        let slow = duration >= self.breakers.config.slow_call_duration;
+
+        self.recorded = true;
+        self.breakers
+            .record(&self.url, self.half_open, Some(CallResult { failed, slow }));
+    }
+}
+
+impl Drop for CircuitPermit {
+    fn drop(&mut self) {
+        // A cancelled call releases its half-open slot without counting as a result
+        if !self.recorded {
+            self.breakers.record(&self.url, self.half_open, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Barrier, thread};
+
+    use super::*;
+
+    const URL: &str = "http://backend";
+
+    fn breakers(open_duration: Duration, half_open_max_calls: usize) -> Arc<CircuitBreakers> {
+        Arc::new(CircuitBreakers::new(CircuitBreakerConfig {
+            window_size: 4,
+            minimum_calls: 4,
+            failure_rate_threshold: 50.0,
+            slow_call_rate_threshold: 100.0,
+            slow_call_duration: Duration::from_secs(1),
+            open_duration,
+            half_open_max_calls,
+        }))
+    }
+
+    fn state(breakers: &CircuitBreakers) -> CircuitState {
+        breakers
+            .statuses()
+            .into_iter()
+            .find(|status| status.url == URL)
+            .map_or(CircuitState::Closed, |status| status.state)
+    }
+
+    fn call(breakers: &Arc<CircuitBreakers>, failed: bool, duration: Duration) {
+        breakers
+            .try_acquire(URL)
+            .expect("permit")
+            .record(failed, duration);
+    }
+
+    fn trip(breakers: &Arc<CircuitBreakers>) {
+        for failed in [false, false, true, true] {
+            call(breakers, failed, Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn stays_closed_below_the_thresholds() {
+        let breakers = breakers(Duration::from_secs(60), 1);
+
+        for failed in [true, false, false, false, true, false] {
+            call(&breakers, failed, Duration::ZERO);
+        }
+
+        assert_eq!(state(&breakers), CircuitState::Closed);
+    }
+
+    #[test]
+    fn waits_for_the_minimum_calls_before_opening() {
+        let breakers = breakers(Duration::from_secs(60), 1);
+
+        for _ in 0..3 {
+            call(&breakers, true, Duration::ZERO);
+        }
+        assert_eq!(state(&breakers), CircuitState::Closed);
+
+        call(&breakers, true, Duration::ZERO);
+        assert_eq!(state(&breakers), CircuitState::Open);
+    }
+
+    #[test]
+    fn opens_on_the_failure_rate_and_rejects_calls() {
+        let breakers = breakers(Duration::from_secs(60), 1);
+
+        trip(&breakers);
+
+        assert_eq!(state(&breakers), CircuitState::Open);
+        assert!(breakers.try_acquire(URL).is_none());
+        assert!(breakers.rejected_servers().contains(URL));
+    }
+
+    #[test]
+    fn opens_on_the_slow_call_rate() {
+        let breakers = breakers(Duration::from_secs(60), 1);
+
+        for _ in 0..4 {
+            call(&breakers, false, Duration::from_secs(2));
+        }
+
+        assert_eq!(state(&breakers), CircuitState::Open);
+    }
+
+    #[test]
+    fn lets_a_probe_through_once_the_cooldown_is_over() {
+        let breakers = breakers(Duration::ZERO, 1);
+        trip(&breakers);
+
+        let probe = breakers.try_acquire(URL).expect("probe permit");
+
+        assert_eq!(state(&breakers), CircuitState::HalfOpen);
+        assert!(breakers.try_acquire(URL).is_none());
+        drop(probe);
+    }
+
+    #[test]
+    fn closes_after_a_successful_probe() {
+        let breakers = breakers(Duration::ZERO, 1);
+        trip(&breakers);
+
+        call(&breakers, false, Duration::ZERO);
+
+        assert_eq!(state(&breakers), CircuitState::Closed);
+        assert!(breakers.rejected_servers().is_empty());
+    }
+
+    #[test]
+    fn reopens_after_a_failed_probe() {
+        let breakers = breakers(Duration::ZERO, 1);
+        trip(&breakers);
+
+        call(&breakers, true, Duration::ZERO);
+
+        assert_eq!(state(&breakers), CircuitState::Open);
+    }
+
+    #[test]
+    fn frees_the_probe_slot_of_a_cancelled_call() {
+        let breakers = breakers(Duration::ZERO, 1);
+        trip(&breakers);
+
+        drop(breakers.try_acquire(URL).expect("probe permit"));
+
+        assert_eq!(state(&breakers), CircuitState::HalfOpen);
+        assert!(breakers.try_acquire(URL).is_some());
+    }
+
+    #[test]
+    fn admits_only_the_allowed_concurrent_probes() {
+        let breakers = breakers(Duration::ZERO, 2);
+        trip(&breakers);
+
+        let barrier = Arc::new(Barrier::new(8));
+        let permits = (0..8)
+            .map(|_| {
+                let breakers = breakers.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    breakers.try_acquire(URL)
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().expect("probe thread"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(permits.iter().flatten().count(), 2);
+        assert_eq!(state(&breakers), CircuitState::HalfOpen);
+    }
+}
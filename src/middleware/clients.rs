@@ -0,0 +1,98 @@
+use std::{collections::HashMap, sync::RwLock, time::Duration};
+
+use reqwest::Url;
+
+use crate::{error::Error, middleware::ServerClient};
+
+/// Connection settings of the HTTP clients used to reach backends
+#[derive(Clone, Copy)]
+pub struct ClientSettings {
+    /// Idle connections kept open per backend
+    pub pool_max_idle_per_host: usize,
+    /// Time an idle connection is kept before being closed
+    pub pool_idle_timeout: Option<Duration>,
+    /// Speak HTTP/2 to backends without negotiating it first
+    pub http2_prior_knowledge: bool,
+    pub tcp_keepalive: Option<Duration>,
+}
+
+/// Long-lived HTTP clients, one per backend and connect timeout, so connections,
+/// keep-alive and TLS sessions are reused across requests.
+///
+/// reqwest only sets the connect timeout on the client, so pools and routes with
+/// different connect timeouts get separate clients for the same backend.
+pub struct ClientRegistry {
+    settings: ClientSettings,
+    clients: RwLock<HashMap<(String, Option<Duration>), reqwest::Client>>,
+}
+
+impl ClientRegistry {
+    pub fn new(settings: ClientSettings) -> Self {
+        Self {
+            settings,
+            clients: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Client for a backend with the given connect timeout, built on first use
+    pub fn get(
+        &self,
+        url: &Url,
+        connect_timeout: Option<Duration>,
+    ) -> Result<reqwest::Client, Error> {
+        let key = (url.to_string(), connect_timeout);
+
+        if let Some(client) = self
+            .clients
+            .read()
+            .ok()
+            .and_then(|clients| clients.get(&key).cloned())
+        {
+            return Ok(client);
+        }
+
+        let mut clients = self
+            .clients
+            .write()
+            .map_err(|_| Error::Other(anyhow::anyhow!("Client registry lock poisoned")))?;
+
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+
+        let client = self
+            .build(connect_timeout)
+            .map_err(|e| Error::Other(e.into()))?;
+        clients.insert(key, client.clone());
+
+        Ok(client)
+    }
+
+    pub fn server_client(
+        &self,
+        url: Url,
+        connect_timeout: Option<Duration>,
+    ) -> Result<ServerClient, Error> {
+        Ok(ServerClient {
+            client: self.get(&url, connect_timeout)?,
+            url,
+        })
+    }
+
+    fn build(&self, connect_timeout: Option<Duration>) -> reqwest::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .pool_max_idle_per_host(self.settings.pool_max_idle_per_host)
+            .pool_idle_timeout(self.settings.pool_idle_timeout)
+            .tcp_keepalive(self.settings.tcp_keepalive);
+
+        if let Some(connect_timeout) = connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+
+        if self.settings.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+
+        builder.build()
+    }
+}
@@ -0,0 +1,53 @@
+use std::sync::Arc;
+
+use crate::{db::RedisClient, error::Error};
+
+/// Counts one in-flight request against a server's load for as long as it is alive.
+///
+/// The load is decremented when the guard is dropped, so a request that finishes,
+/// fails or is cancelled by the client always releases its slot.
+pub struct ConnectionGuard {
+    redis_conn: RedisClient,
+    instance_id: Arc<str>,
+    url: String,
+}
+
+impl ConnectionGuard {
+    pub async fn acquire(
+        mut redis_conn: RedisClient,
+        instance_id: Arc<str>,
+        url: &str,
+    ) -> Result<Self, Error> {
+        redis_conn
+            .increment_server_load(&instance_id, url, 1)
+            .await?;
+
+        Ok(Self {
+            redis_conn,
+            instance_id,
+            url: url.to_string(),
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            tracing::warn!("No runtime to release load of {}", self.url);
+            return;
+        };
+
+        let mut redis_conn = self.redis_conn.clone();
+        let instance_id = self.instance_id.clone();
+        let url = std::mem::take(&mut self.url);
+
+        runtime.spawn(async move {
+            if let Err(e) = redis_conn
+                .increment_server_load(&instance_id, &url, -1)
+                .await
+            {
+                tracing::warn!("Failed to release load of {}: {}", url, e);
+            }
+        });
+    }
+}
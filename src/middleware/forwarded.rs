@@ -0,0 +1,231 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue, header::FORWARDED, header::HOST};
+use ipnet::IpNet;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// The balancer only accepts plain HTTP connections
+const LOCAL_PROTO: &str = "http";
+
+/// Adds `X-Forwarded-*` and RFC 7239 `Forwarded` headers describing the client connection.
+///
+/// Values already on the request are only kept (and appended to) when the peer is one of the
+/// trusted proxies; otherwise they are replaced so clients cannot spoof their address.
+pub fn add_forwarded_headers(headers: &mut HeaderMap, peer: SocketAddr, trusted_proxies: &[IpNet]) {
+    let peer_ip = peer.ip().to_canonical();
+    let trusted = trusted_proxies.iter().any(|net| net.contains(&peer_ip));
+
+    let host = headers
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_string);
+
+    let (forwarded_proto, forwarded_host) = if trusted {
+        (
+            header_str(headers, &X_FORWARDED_PROTO),
+            header_str(headers, &X_FORWARDED_HOST).or_else(|| host.clone()),
+        )
+    } else {
+        (None, host.clone())
+    };
+    let proto = forwarded_proto.unwrap_or_else(|| LOCAL_PROTO.to_string());
+
+    let forwarded_for = appended(headers, &X_FORWARDED_FOR, trusted, peer_ip.to_string());
+
+    let mut forwarded_element = format!("for={};proto={}", forwarded_node(peer_ip), proto);
+    if let Some(host) = &host {
+        forwarded_element.push_str(&format!(";host={}", quoted(host)));
+    }
+    let forwarded = appended(headers, &FORWARDED, trusted, forwarded_element);
+
+    set_header(headers, X_FORWARDED_FOR, &forwarded_for);
+    set_header(headers, FORWARDED, &forwarded);
+    set_header(headers, X_FORWARDED_PROTO, &proto);
+
+    match forwarded_host {
+        Some(forwarded_host) => set_header(headers, X_FORWARDED_HOST, &forwarded_host),
+        None => {
+            headers.remove(X_FORWARDED_HOST);
+        }
+    }
+}
+
+/// Parses a comma-separated list of CIDRs (bare addresses are treated as single hosts)
+pub fn parse_trusted_proxies(value: &str) -> anyhow::Result<Vec<IpNet>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|cidr| !cidr.is_empty())
+        .map(|cidr| {
+            cidr.parse::<IpNet>()
+                .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| anyhow::anyhow!("Invalid trusted proxy CIDR: {cidr}"))
+        })
+        .collect()
+}
+
+/// Joins the existing values of a list header with a new element, dropping them if untrusted
+fn appended(headers: &HeaderMap, name: &HeaderName, trusted: bool, element: String) -> String {
+    let mut values = Vec::new();
+
+    if trusted {
+        values.extend(
+            headers
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .map(str::to_string),
+        );
+    }
+
+    values.push(element);
+    values.join(", ")
+}
+
+fn header_str(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.split(',').next().unwrap_or(h).trim().to_string())
+}
+
+fn set_header(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    match HeaderValue::from_str(value) {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(_) => tracing::warn!("Skipping invalid {} header value", name),
+    }
+}
+
+/// RFC 7239 node identifier: IPv6 addresses must be bracketed and quoted
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{ip}]\""),
+    }
+}
+
+/// Quotes a value if it is not a valid RFC 7230 token (e.g. `host:port`)
+fn quoted(value: &str) -> String {
+    let is_token = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted() -> Vec<IpNet> {
+        parse_trusted_proxies("10.0.0.0/8, ::1").expect("trusted proxies")
+    }
+
+    fn spoofed_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, HeaderValue::from_static("example.com"));
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("1.2.3.4"));
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("https"));
+        headers.insert(X_FORWARDED_HOST, HeaderValue::from_static("evil.example"));
+        headers.insert(
+            FORWARDED,
+            HeaderValue::from_static("for=1.2.3.4;proto=https"),
+        );
+        headers
+    }
+
+    fn forward(mut headers: HeaderMap, peer: &str) -> HeaderMap {
+        add_forwarded_headers(
+            &mut headers,
+            peer.parse().expect("peer address"),
+            &trusted(),
+        );
+        headers
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &HeaderName) -> &'a str {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .expect("header")
+    }
+
+    #[test]
+    fn appends_to_the_headers_of_trusted_proxies() {
+        let headers = forward(spoofed_headers(), "10.1.2.3:5000");
+
+        assert_eq!(header(&headers, &X_FORWARDED_FOR), "1.2.3.4, 10.1.2.3");
+        assert_eq!(header(&headers, &X_FORWARDED_PROTO), "https");
+        assert_eq!(header(&headers, &X_FORWARDED_HOST), "evil.example");
+        assert_eq!(
+            header(&headers, &FORWARDED),
+            "for=1.2.3.4;proto=https, for=10.1.2.3;proto=https;host=example.com"
+        );
+    }
+
+    #[test]
+    fn replaces_the_headers_of_untrusted_clients() {
+        let headers = forward(spoofed_headers(), "203.0.113.7:5000");
+
+        assert_eq!(header(&headers, &X_FORWARDED_FOR), "203.0.113.7");
+        assert_eq!(header(&headers, &X_FORWARDED_PROTO), "http");
+        assert_eq!(header(&headers, &X_FORWARDED_HOST), "example.com");
+        assert_eq!(
+            header(&headers, &FORWARDED),
+            "for=203.0.113.7;proto=http;host=example.com"
+        );
+    }
+
+    #[test]
+    fn drops_a_spoofed_forwarded_host_without_a_host_header() {
+        let mut headers = spoofed_headers();
+        headers.remove(HOST);
+
+        let headers = forward(headers, "203.0.113.7:5000");
+
+        assert!(headers.get(X_FORWARDED_HOST).is_none());
+        assert_eq!(header(&headers, &FORWARDED), "for=203.0.113.7;proto=http");
+    }
+
+    #[test]
+    fn quotes_ipv6_clients_in_forwarded() {
+        let headers = forward(HeaderMap::new(), "[2001:db8::1]:5000");
+
+        assert_eq!(header(&headers, &X_FORWARDED_FOR), "2001:db8::1");
+        assert_eq!(
+            header(&headers, &FORWARDED),
+            "for=\"[2001:db8::1]\";proto=http"
+        );
+    }
+
+    #[test]
+    fn trusts_ipv6_proxies_and_ipv4_mapped_addresses() {
+        let headers = forward(spoofed_headers(), "[::1]:5000");
+        assert_eq!(header(&headers, &X_FORWARDED_FOR), "1.2.3.4, ::1");
+
+        let headers = forward(spoofed_headers(), "[::ffff:10.1.2.3]:5000");
+        assert_eq!(header(&headers, &X_FORWARDED_FOR), "1.2.3.4, 10.1.2.3");
+    }
+
+    #[test]
+    fn quotes_hosts_with_a_port() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, HeaderValue::from_static("example.com:8080"));
+
+        let headers = forward(headers, "203.0.113.7:5000");
+
+        assert_eq!(
+            header(&headers, &FORWARDED),
+            "for=203.0.113.7;proto=http;host=\"example.com:8080\""
+        );
+    }
+}
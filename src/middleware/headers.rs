@@ -0,0 +1,59 @@
+use axum::http::{
+    HeaderMap, HeaderName,
+    header::{
+        CONNECTION, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER, TRANSFER_ENCODING,
+        UPGRADE,
+    },
+};
+
+/// Hop-by-hop headers (RFC 9110 §7.6.1) that only apply to a single connection
+const HOP_BY_HOP_HEADERS: [HeaderName; 7] = [
+    CONNECTION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+];
+
+/// Non-standard hop-by-hop headers still sent by some clients
+const LEGACY_HOP_BY_HOP_HEADERS: [&str; 2] = ["keep-alive", "proxy-connection"];
+
+/// Removes hop-by-hop headers, including any header named in `Connection`.
+pub fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let connection_headers = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+
+    for name in connection_headers {
+        headers.remove(name);
+    }
+
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+
+    for name in LEGACY_HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+/// Prepares client headers for the upstream request.
+///
+/// `Host` is dropped as reqwest derives it from the upstream URL.
+pub fn upstream_request_headers(mut headers: HeaderMap) -> HeaderMap {
+    remove_hop_by_hop_headers(&mut headers);
+    headers.remove(HOST);
+    headers
+}
+
+/// Prepares backend headers for the client response.
+pub fn downstream_response_headers(mut headers: HeaderMap) -> HeaderMap {
+    remove_hop_by_hop_headers(&mut headers);
+    headers
+}
@@ -1,40 +1,109 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
 use axum::{
-    body::{BodyDataStream, Bytes},
-    extract::State,
-    http::Request,
+    extract::{ConnectInfo, State},
+    http::{
+        HeaderValue, Request,
+        header::{CONTENT_LENGTH, TRANSFER_ENCODING},
+    },
     middleware::Next,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
-use futures_util::stream::StreamExt;
-use serde_json::Value;
 
-use crate::{config::State as AppState, error::Error};
+use crate::{
+    algorithms::RequestContext,
+    config::{LatencyTracking, State as AppState},
+    error::Error,
+    middleware::{
+        affinity::AffinityToken,
+        request_id::{X_REQUEST_ID, ensure_request_id},
+        retry::RetryBody,
+    },
+    route::is_local_route,
+    services::RequestOutcome,
+};
 
+mod affinity;
+mod circuit_breaker;
+mod clients;
+mod connection_guard;
+mod forwarded;
+mod headers;
+mod request_id;
+mod retry;
+mod routing;
 mod server;
+mod timeouts;
+mod upstream_uri;
 
-pub use server::{ServerClient, StaticServerData};
+pub use affinity::SessionAffinity;
+pub use circuit_breaker::{
+    CircuitBreakerConfig, CircuitBreakerStatus, CircuitBreakers, CircuitPermit,
+};
+pub use clients::{ClientRegistry, ClientSettings};
+pub use forwarded::parse_trusted_proxies;
+pub use retry::{RetryBudget, RetryOn, RetryPolicy};
+pub use routing::{
+    DEFAULT_POOL, RoutingTable, UpstreamPool, parse_pool_settings, parse_pools, pool_config,
+};
+pub use server::{ServerClient, StaticServerData, StatusProbe};
+pub use timeouts::{
+    TimeoutCounts, TimeoutKind, TimeoutMetrics, TimeoutTable, UpstreamTimeouts, timeout_from_millis,
+};
+pub use upstream_uri::{PathRewrite, upstream_url};
 
 /// Middleware function to route requests to appropriate servers
 pub async fn request_route(
-    State(mut state): State<AppState>,
-    req: Request<axum::body::Body>,
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut req: Request<axum::body::Body>,
     next: Next,
-) -> Result<impl IntoResponse, Error> {
-    if req.uri().path().starts_with("/status") {
-        return Ok(next.run(req).await);
+) -> Response {
+    if is_local_route(req.method(), req.uri().path()) {
+        return next.run(req).await;
+    }
+
+    let request_id = ensure_request_id(req.headers_mut());
+
+    tracing::info!(
+        "New Request Received: {} {} ({})",
+        req.method(),
+        req.uri().path(),
+        request_id
+    );
+
+    let error_responder = state.error_responder;
+
+    let mut response = match proxy_request(state, peer, req).await {
+        Ok(response) => response,
+        Err(e) => error_responder.respond(&e, Some(&request_id)),
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(X_REQUEST_ID, value);
     }
 
-    let (parts, body) = req.into_parts();
+    response
+}
 
-    tracing::info!("New Request Received");
+/// Forwards a request to a backend, retrying on other backends when allowed
+async fn proxy_request(
+    mut state: AppState,
+    peer: SocketAddr,
+    req: Request<axum::body::Body>,
+) -> Result<Response, Error> {
+    let (mut parts, body) = req.into_parts();
 
-    // TODO: How expensive is this?
-    let json_body = BodyBytes::from_body_data_stream(body.into_data_stream())
-        .await?
-        .to_json()
-        .ok();
+    // A request only carries a body when it is framed by one of these headers
+    let has_body =
+        parts.headers.contains_key(CONTENT_LENGTH) || parts.headers.contains_key(TRANSFER_ENCODING);
+    let body = has_body.then_some(body);
 
-    let route = parts.uri.to_string();
+    forwarded::add_forwarded_headers(&mut parts.headers, peer, &state.trusted_proxies);
 
     let location = parts
         .headers
@@ -42,48 +111,234 @@ pub async fn request_route(
         .and_then(|l| l.to_str().ok())
         .unwrap_or(&state.default_location);
 
-    let server_client = state
-        .algorithm
-        .select_server(state.redis_conn.clone(), location)
-        .await?;
+    let hash_key = state.hash_key.extract(&parts.headers, &parts.uri, peer);
+    let context = RequestContext::new(&parts, location, hash_key.as_deref());
+
+    let _active_request = state.retry_policy.budget.start_request();
+
+    let pool = state.routing.pool_for(&parts).clone();
+    let timeouts = pool.timeouts.for_path(parts.uri.path());
+    let deadline = timeouts.total.map(|total| Instant::now() + total);
+
+    let mut body = RetryBody::new(body, &parts.method, &parts.headers, &state.retry_policy).await?;
+    let retryable = body.is_replayable() && state.retry_policy.allows(&parts.method);
+
+    // Servers whose circuit is open are skipped in favour of the other backends
+    let mut exclude = state.circuit_breakers.rejected_servers();
 
-    let start_time = std::time::Instant::now();
+    // A client with a valid affinity token keeps its server while that server is routable
+    let affinity_token = state
+        .session_affinity
+        .as_ref()
+        .and_then(|affinity| affinity.read(&parts.headers));
 
-    let response = server_client
-        .handle_request(
-            parts.method,
-            route.trim_start_matches('/'),
-            json_body,
+    let mut target = match affinity_target(
+        &state,
+        &pool,
+        affinity_token.as_ref(),
+        &exclude,
+        timeouts.connect,
+    )
+    .await?
+    {
+        Some(target) => target,
+        None => select_target(&state, &pool, &context, &mut exclude, timeouts.connect).await?,
+    };
+    let mut retries = 0;
+    let mut _active_retry = None;
+
+    loop {
+        let (server_client, permit) = target;
+
+        let url = upstream_url(&server_client.url, &parts.uri, &pool.path_rewrite)?;
+
+        let start_time = Instant::now();
+
+        // The attempt is bounded by whichever of the per-try and total timeouts ends first
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(start_time));
+        let attempt_timeout = match (timeouts.per_try, remaining) {
+            (Some(per_try), Some(remaining)) if remaining < per_try => {
+                Some((remaining, TimeoutKind::Total))
+            }
+            (Some(per_try), _) => Some((per_try, TimeoutKind::PerTry)),
+            (None, Some(remaining)) => Some((remaining, TimeoutKind::Total)),
+            (None, None) => None,
+        };
+
+        let request = server_client.handle_request(
+            parts.method.clone(),
+            url,
+            parts.headers.clone(),
+            body.next(),
             state.redis_conn.clone(),
-        )
-        .await?;
+            state.instance_id.clone(),
+        );
 
-    let latency = start_time.elapsed().as_millis();
+        let response = match attempt_timeout {
+            Some((timeout, kind)) => tokio::time::timeout(timeout, request)
+                .await
+                .unwrap_or(Err(Error::UpstreamTimeout(kind))),
+            None => request.await,
+        };
 
-    // TODO: move to background
-    state
-        .redis_conn
-        .update_server_latency_record(server_client.url.as_str(), latency)
-        .await?;
+        if let Err(Error::UpstreamTimeout(kind)) = &response {
+            tracing::warn!("Request to {} hit the {} timeout", server_client.url, kind);
+            state.timeout_metrics.record(*kind);
+        }
 
-    Ok(response.into_response())
-}
+        let outcome = match &response {
+            Ok(response) => Some(RequestOutcome::Response(response.status())),
+            Err(Error::Upstream(_) | Error::UpstreamTimeout(_)) => {
+                Some(RequestOutcome::GatewayError)
+            }
+            // Errors of the balancer itself, such as Redis failures, say nothing about the
+            // backend; the permit is released without counting as a call
+            Err(_) => None,
+        };
 
-struct BodyBytes(Bytes);
+        if let Some(outcome) = outcome {
+            let failed = match outcome {
+                RequestOutcome::Response(status) => status.is_server_error(),
+                RequestOutcome::GatewayError => true,
+            };
+            permit.record(failed, start_time.elapsed());
 
-impl BodyBytes {
-    async fn from_body_data_stream(mut body_stream: BodyDataStream) -> anyhow::Result<Self> {
-        let mut body = Vec::new();
+            state
+                .outlier_detector
+                .record(&mut state.redis_conn, server_client.url.as_str(), outcome)
+                .await;
+        }
 
-        while let Some(chunk_result) = body_stream.next().await {
-            let chunk = chunk_result?;
-            body.extend_from_slice(&chunk);
+        // Retries go to a server that has not been tried yet
+        if retryable && state.retry_policy.should_retry(retries, &response) {
+            exclude.insert(server_client.url.to_string());
+
+            match state.retry_policy.budget.try_retry() {
+                Some(active_retry) => {
+                    match select_target(&state, &pool, &context, &mut exclude, timeouts.connect)
+                        .await
+                    {
+                        Ok(next) => {
+                            retries += 1;
+                            tracing::warn!(
+                                "Retrying request to {} on {} (retry {}/{})",
+                                server_client.url,
+                                next.0.url,
+                                retries,
+                                state.retry_policy.max_retries
+                            );
+                            target = next;
+                            _active_retry = Some(active_retry);
+                            continue;
+                        }
+                        Err(e) => tracing::warn!("No server left to retry on: {}", e),
+                    }
+                }
+                None => tracing::warn!("Retry budget exhausted, not retrying"),
+            }
         }
 
-        Ok(BodyBytes(Bytes::from(body)))
+        let mut response = response?;
+
+        if let Some(idle) = timeouts.idle {
+            response = response.with_idle_timeout(idle, state.timeout_metrics.clone());
+        }
+
+        let elapsed = start_time.elapsed();
+        pool.balancer
+            .observe_latency(server_client.url.as_str(), elapsed);
+
+        let latency = elapsed.as_millis();
+
+        // TODO: move to background
+        match state.latency_tracking {
+            LatencyTracking::Window { size, .. } => {
+                state
+                    .redis_conn
+                    .update_server_latency_record(server_client.url.as_str(), latency, size)
+                    .await?
+            }
+            LatencyTracking::Ewma { alpha } => {
+                state
+                    .redis_conn
+                    .update_server_latency_ewma(server_client.url.as_str(), latency, alpha)
+                    .await?
+            }
+        }
+
+        let mut response = response.into_response();
+
+        if let Some(affinity) = &state.session_affinity
+            && affinity.needs_update(affinity_token.as_ref(), server_client.url.as_str())
+        {
+            affinity.write(response.headers_mut(), server_client.url.as_str());
+        }
+
+        return Ok(response);
     }
+}
+
+/// The server named by the request's affinity token, if it is routable and its circuit
+/// lets the call through
+async fn affinity_target(
+    state: &AppState,
+    pool: &UpstreamPool,
+    token: Option<&AffinityToken>,
+    exclude: &HashSet<String>,
+    connect_timeout: Option<Duration>,
+) -> Result<Option<(ServerClient, CircuitPermit)>, Error> {
+    let (Some(affinity), Some(token)) = (&state.session_affinity, token) else {
+        return Ok(None);
+    };
+
+    let mut redis_conn = state.redis_conn.clone();
+    let servers = pool
+        .balancer
+        .routable_servers(&mut redis_conn, exclude)
+        .await?;
+
+    let Some(url) = affinity.resolve(token, servers.keys()) else {
+        return Ok(None);
+    };
+
+    let Some(permit) = state.circuit_breakers.try_acquire(url) else {
+        return Ok(None);
+    };
+
+    let url = url.parse().map_err(|_| Error::InvalidUrl)?;
+
+    Ok(Some((
+        state.clients.server_client(url, connect_timeout)?,
+        permit,
+    )))
+}
+
+/// Selects a server outside `exclude` whose circuit lets the call through
+async fn select_target(
+    state: &AppState,
+    pool: &UpstreamPool,
+    context: &RequestContext<'_>,
+    exclude: &mut HashSet<String>,
+    connect_timeout: Option<Duration>,
+) -> Result<(ServerClient, CircuitPermit), Error> {
+    loop {
+        let server_client = pool
+            .balancer
+            .select_server(
+                state.redis_conn.clone(),
+                context,
+                exclude,
+                &state.clients,
+                connect_timeout,
+            )
+            .await?;
 
-    fn to_json(&self) -> Result<Value, serde_json::Error> {
-        serde_json::from_slice(&self.0)
+        match state
+            .circuit_breakers
+            .try_acquire(server_client.url.as_str())
+        {
+            Some(permit) => return Ok((server_client, permit)),
+            None => exclude.insert(server_client.url.to_string()),
+        };
     }
 }
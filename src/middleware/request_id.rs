@@ -0,0 +1,29 @@
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use rand::Rng as _;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest client-supplied request ID that is kept
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Returns the request's ID, generating one when the client did not send a usable one.
+///
+/// The ID is written back to the headers so the backend receives it too.
+pub fn ensure_request_id(headers: &mut HeaderMap) -> String {
+    let existing = headers
+        .get(&X_REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_string);
+
+    if let Some(id) = existing {
+        return id;
+    }
+
+    let id = format!("{:032x}", rand::rng().random::<u128>());
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        headers.insert(X_REQUEST_ID, value);
+    }
+
+    id
+}
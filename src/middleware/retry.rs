@@ -0,0 +1,237 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, Method, header::CONTENT_LENGTH},
+};
+use reqwest::StatusCode;
+
+use crate::{
+    error::Error,
+    middleware::{server::ApiResponse, timeouts::TimeoutKind},
+};
+
+/// When and how often a failed request is sent to another server
+pub struct RetryPolicy {
+    /// Retries allowed after the first attempt
+    pub max_retries: u32,
+    pub retry_on: RetryOn,
+    /// Whether methods that are not idempotent (`POST`, `PATCH`) may be retried
+    pub retry_non_idempotent: bool,
+    /// Largest request body buffered so it can be resent; larger or chunked bodies are streamed and never retried
+    pub max_body_bytes: usize,
+    pub budget: RetryBudget,
+}
+
+/// Failures that trigger a retry
+#[derive(Default)]
+pub struct RetryOn {
+    pub connect_failure: bool,
+    pub timeout: bool,
+    /// Connection errors after the request was sent, such as a reset
+    pub reset: bool,
+    pub statuses: Vec<StatusCode>,
+}
+
+impl RetryOn {
+    /// Parses `connect-failure,timeout,reset,5xx,502,503`
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let mut retry_on = Self::default();
+
+        for condition in value.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+            match condition {
+                "connect-failure" => retry_on.connect_failure = true,
+                "timeout" => retry_on.timeout = true,
+                "reset" => retry_on.reset = true,
+                "5xx" => retry_on
+                    .statuses
+                    .extend((500..600).filter_map(|code| StatusCode::from_u16(code).ok())),
+                code => retry_on.statuses.push(
+                    code.parse::<u16>()
+                        .ok()
+                        .and_then(|code| StatusCode::from_u16(code).ok())
+                        .ok_or_else(|| anyhow::anyhow!("Invalid retry condition '{code}'"))?,
+                ),
+            }
+        }
+
+        Ok(retry_on)
+    }
+
+    fn matches(&self, response: &Result<ApiResponse, Error>) -> bool {
+        match response {
+            Ok(response) => self.statuses.contains(&response.status()),
+            Err(Error::UpstreamTimeout(TimeoutKind::Connect)) => {
+                self.connect_failure || self.timeout
+            }
+            Err(Error::UpstreamTimeout(TimeoutKind::PerTry | TimeoutKind::Idle)) => self.timeout,
+            // The request is out of time, another attempt would fail immediately
+            Err(Error::UpstreamTimeout(TimeoutKind::Total)) => false,
+            Err(Error::Upstream(e)) if e.is_connect() => self.connect_failure,
+            Err(Error::Upstream(e)) if e.is_timeout() => self.timeout,
+            Err(Error::Upstream(_)) => self.reset,
+            Err(_) => false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether a request with this method may be retried at all
+    pub fn allows(&self, method: &Method) -> bool {
+        self.max_retries > 0 && (method.is_idempotent() || self.retry_non_idempotent)
+    }
+
+    /// Whether the result of an attempt should be retried on another server
+    pub fn should_retry(&self, retries: u32, response: &Result<ApiResponse, Error>) -> bool {
+        retries < self.max_retries && self.retry_on.matches(response)
+    }
+}
+
+/// Caps the number of concurrent retries to a share of the active requests, so a
+/// failing backend does not multiply the load on the others
+pub struct RetryBudget {
+    /// Concurrent retries allowed, in percent of the active requests
+    pub budget_percent: f64,
+    /// Concurrent retries always allowed, however few requests are active
+    pub min_retry_concurrency: usize,
+    active_requests: Arc<AtomicUsize>,
+    active_retries: Arc<AtomicUsize>,
+}
+
+impl RetryBudget {
+    pub fn new(budget_percent: f64, min_retry_concurrency: usize) -> Self {
+        Self {
+            budget_percent,
+            min_retry_concurrency,
+            active_requests: Arc::new(AtomicUsize::new(0)),
+            active_retries: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Counts a request as active until the returned guard is dropped
+    pub fn start_request(&self) -> ActiveGuard {
+        ActiveGuard::increment(&self.active_requests)
+    }
+
+    /// Takes a retry out of the budget, released when the returned guard is dropped
+    pub fn try_retry(&self) -> Option<ActiveGuard> {
+        let active_requests = self.active_requests.load(Ordering::Relaxed);
+        let allowed = ((active_requests as f64 * self.budget_percent / 100.0) as usize)
+            .max(self.min_retry_concurrency);
+
+        self.active_retries
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |retries| {
+                (retries < allowed).then_some(retries + 1)
+            })
+            .ok()
+            .map(|_| ActiveGuard(self.active_retries.clone()))
+    }
+}
+
+/// Decrements an active counter when dropped
+pub struct ActiveGuard(Arc<AtomicUsize>);
+
+impl ActiveGuard {
+    fn increment(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::AcqRel);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Request body that is buffered when its request may be retried and it is small enough
+/// to be resent; every other body is streamed through
+pub enum RetryBody {
+    Empty,
+    Buffered(Bytes),
+    Streaming(Option<Body>),
+}
+
+impl RetryBody {
+    pub async fn new(
+        body: Option<Body>,
+        method: &Method,
+        headers: &HeaderMap,
+        policy: &RetryPolicy,
+    ) -> Result<Self, Error> {
+        let Some(body) = body else {
+            return Ok(Self::Empty);
+        };
+
+        if !policy.allows(method) {
+            return Ok(Self::Streaming(Some(body)));
+        }
+
+        let content_length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<usize>().ok());
+
+        match content_length {
+            Some(length) if length <= policy.max_body_bytes => {
+                let bytes = axum::body::to_bytes(body, policy.max_body_bytes)
+                    .await
+                    .map_err(|e| Error::Other(e.into()))?;
+                Ok(Self::Buffered(bytes))
+            }
+            _ => Ok(Self::Streaming(Some(body))),
+        }
+    }
+
+    /// Whether the body can be sent more than once
+    pub fn is_replayable(&self) -> bool {
+        !matches!(self, Self::Streaming(_))
+    }
+
+    /// Body for the next attempt
+    pub fn next(&mut self) -> Option<Body> {
+        match self {
+            Self::Empty => None,
+            Self::Buffered(bytes) => Some(Body::from(bytes.clone())),
+            Self::Streaming(body) => body.take(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            retry_on: RetryOn::parse("connect-failure").expect("retry on"),
+            retry_non_idempotent: false,
+            max_body_bytes: 1024,
+            budget: RetryBudget::new(20.0, 3),
+        }
+    }
+
+    async fn body(method: Method, policy: &RetryPolicy) -> RetryBody {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, 7.into());
+
+        RetryBody::new(Some(Body::from("payload")), &method, &headers, policy)
+            .await
+            .expect("retry body")
+    }
+
+    #[tokio::test]
+    async fn buffers_small_bodies_of_retryable_requests() {
+        assert!(body(Method::PUT, &policy(2)).await.is_replayable());
+    }
+
+    #[tokio::test]
+    async fn streams_bodies_of_requests_that_are_never_retried() {
+        assert!(!body(Method::POST, &policy(2)).await.is_replayable());
+        assert!(!body(Method::PUT, &policy(0)).await.is_replayable());
+    }
+}
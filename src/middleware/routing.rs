@@ -0,0 +1,325 @@
+use std::{collections::HashMap, str::FromStr as _, sync::Arc};
+
+use axum::http::{HeaderName, Method, header::HOST, request::Parts};
+use regex::Regex;
+
+use crate::{
+    algorithms::Balancer,
+    config::SystemConfig,
+    middleware::{PathRewrite, StaticServerData, TimeoutTable},
+    services::HealthCheck,
+};
+
+/// Name of the pool built from `AVAILABLE_SERVERS`, used by requests no route matches
+pub const DEFAULT_POOL: &str = "default";
+
+/// Named group of backends with its own balancing, health checks and timeouts
+pub struct UpstreamPool {
+    pub name: String,
+    pub servers: Vec<StaticServerData>,
+    pub balancer: Balancer,
+    pub health_check: Arc<HealthCheck>,
+    pub timeouts: TimeoutTable,
+    pub path_rewrite: PathRewrite,
+}
+
+/// Parses `name=url|weight,url|weight;name=url|weight` pools
+pub fn parse_pools(value: &str) -> anyhow::Result<Vec<(String, Vec<StaticServerData>)>> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|pool| !pool.is_empty())
+        .map(|pool| {
+            let (name, servers) = pool.split_once('=').ok_or_else(|| {
+                anyhow::anyhow!("Invalid upstream pool '{pool}', expected 'name=url|weight,...'")
+            })?;
+
+            let name = name.trim();
+            if name == DEFAULT_POOL {
+                anyhow::bail!("The '{DEFAULT_POOL}' pool is configured with AVAILABLE_SERVERS");
+            }
+
+            let servers = servers
+                .split(',')
+                .map(str::trim)
+                .filter(|server| !server.is_empty())
+                .map(StaticServerData::new)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
+            Ok((name.to_string(), servers))
+        })
+        .collect()
+}
+
+/// Parses `name=key:value,key:value;name=key:value` pool settings
+pub fn parse_pool_settings(value: &str) -> anyhow::Result<HashMap<String, String>> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|pool| !pool.is_empty())
+        .map(|pool| {
+            let (name, settings) = pool.split_once('=').ok_or_else(|| {
+                anyhow::anyhow!("Invalid pool settings '{pool}', expected 'name=key:value,...'")
+            })?;
+            Ok((name.trim().to_string(), settings.to_string()))
+        })
+        .collect()
+}
+
+/// Applies a pool's `key:value,key:value` settings on top of the global configuration
+pub fn pool_config(config: &SystemConfig, settings: &str) -> anyhow::Result<SystemConfig> {
+    let mut config = config.clone();
+
+    for setting in settings.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (key, value) = setting.split_once(':').ok_or_else(|| {
+            anyhow::anyhow!("Invalid pool setting '{setting}', expected 'key:value'")
+        })?;
+        let value = value.trim();
+
+        match key.trim() {
+            "algorithm" => config.algorithm = value.to_string(),
+            "health_check_type" => config.health_check_type = value.to_string(),
+            "health_check_method" => config.health_check_method = value.to_string(),
+            "health_check_path" => config.health_check_path = value.to_string(),
+            // `;` separates pools, so headers are separated by `|` instead
+            "health_check_headers" => config.health_check_headers = value.replace('|', ";"),
+            "health_check_expected_status" => {
+                config.health_check_expected_status = value.to_string();
+            }
+            // A pool's body match replaces the global one, whichever kind it is
+            "health_check_body_contains" => {
+                config.health_check_body_contains = Some(value.to_string());
+                config.health_check_body_regex = None;
+            }
+            "health_check_body_regex" => {
+                config.health_check_body_regex = Some(value.to_string());
+                config.health_check_body_contains = None;
+            }
+            "health_check_interval_secs" => config.health_check_interval_secs = value.parse()?,
+            "health_check_timeout_ms" => config.health_check_timeout_ms = value.parse()?,
+            "health_check_jitter_ms" => config.health_check_jitter_ms = value.parse()?,
+            "connect_timeout_ms" => config.upstream_connect_timeout_ms = value.parse()?,
+            "per_try_timeout_ms" => config.upstream_per_try_timeout_ms = value.parse()?,
+            "total_timeout_ms" => config.upstream_total_timeout_ms = value.parse()?,
+            "idle_timeout_ms" => config.upstream_idle_timeout_ms = value.parse()?,
+            "strip_prefix" => config.strip_prefix = Some(value.to_string()),
+            "add_prefix" => config.add_prefix = Some(value.to_string()),
+            other => anyhow::bail!("Unknown pool setting '{other}'"),
+        }
+    }
+
+    Ok(config)
+}
+
+/// Path condition of a route
+enum PathMatch {
+    Prefix(String),
+    Regex(Regex),
+}
+
+/// Conditions a request must all meet to be sent to `pool`
+struct Route {
+    /// Lowercase host, optionally starting with `*.` to match any subdomain
+    host: Option<String>,
+    path: Option<PathMatch>,
+    methods: Vec<Method>,
+    /// Headers that must be present, with the value they must have if any
+    headers: Vec<(HeaderName, Option<String>)>,
+    pool: Arc<UpstreamPool>,
+}
+
+impl Route {
+    fn matches(&self, parts: &Parts) -> bool {
+        let host_matches = self.host.as_ref().is_none_or(|pattern| {
+            request_host(parts).is_some_and(|host| match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|subdomain| subdomain.ends_with('.')),
+                None => host == *pattern,
+            })
+        });
+
+        let path_matches = self.path.as_ref().is_none_or(|path| match path {
+            PathMatch::Prefix(prefix) => parts.uri.path().starts_with(prefix.as_str()),
+            PathMatch::Regex(regex) => regex.is_match(parts.uri.path()),
+        });
+
+        let method_matches = self.methods.is_empty() || self.methods.contains(&parts.method);
+
+        let headers_match = self.headers.iter().all(|(name, expected)| {
+            parts.headers.get_all(name).iter().any(|value| {
+                expected
+                    .as_ref()
+                    .is_none_or(|expected| value.to_str().is_ok_and(|value| value == expected))
+            })
+        });
+
+        host_matches && path_matches && method_matches && headers_match
+    }
+}
+
+/// Routes requests to upstream pools, the first matching route winning
+pub struct RoutingTable {
+    routes: Vec<Route>,
+    pools: Vec<Arc<UpstreamPool>>,
+    default: Arc<UpstreamPool>,
+}
+
+impl RoutingTable {
+    /// Parses `pool: condition, condition; pool: condition` routes over `pools`, which must
+    /// include the [`DEFAULT_POOL`].
+    ///
+    /// Conditions are `host=<host>`, `prefix=<path>`, `regex=<path regex>`,
+    /// `method=<method>|<method>` and `header=<name>` or `header=<name>:<value>`.
+    pub fn parse(routes: &str, pools: Vec<UpstreamPool>) -> anyhow::Result<Self> {
+        let mut by_name = HashMap::new();
+        for pool in pools {
+            let name = pool.name.clone();
+            if by_name.insert(name.clone(), Arc::new(pool)).is_some() {
+                anyhow::bail!("Upstream pool '{name}' is defined more than once");
+            }
+        }
+        let pools = by_name;
+
+        let default = pools
+            .get(DEFAULT_POOL)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Missing the '{DEFAULT_POOL}' upstream pool"))?;
+
+        let routes = routes
+            .split(';')
+            .map(str::trim)
+            .filter(|route| !route.is_empty())
+            .map(|route| {
+                let (pool, conditions) = route.split_once(':').ok_or_else(|| {
+                    anyhow::anyhow!("Invalid route '{route}', expected 'pool: conditions'")
+                })?;
+
+                let pool = pools
+                    .get(pool.trim())
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("Route '{route}' targets an unknown pool"))?;
+
+                parse_route(conditions, pool)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut pools = pools.into_values().collect::<Vec<_>>();
+        pools.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(Self {
+            routes,
+            pools,
+            default,
+        })
+    }
+
+    /// Pool a request is sent to
+    pub fn pool_for(&self, parts: &Parts) -> &Arc<UpstreamPool> {
+        self.routes
+            .iter()
+            .find(|route| route.matches(parts))
+            .map_or(&self.default, |route| &route.pool)
+    }
+
+    /// Every pool, sorted by name
+    pub fn pools(&self) -> &[Arc<UpstreamPool>] {
+        &self.pools
+    }
+}
+
+fn parse_route(conditions: &str, pool: Arc<UpstreamPool>) -> anyhow::Result<Route> {
+    let mut route = Route {
+        host: None,
+        path: None,
+        methods: Vec::new(),
+        headers: Vec::new(),
+        pool,
+    };
+
+    for condition in conditions
+        .split(',')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+    {
+        let (kind, value) = condition.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!("Invalid route condition '{condition}', expected 'kind=value'")
+        })?;
+        let value = value.trim();
+
+        match kind.trim() {
+            "host" => route.host = Some(value.to_lowercase()),
+            "prefix" => route.path = Some(PathMatch::Prefix(value.to_string())),
+            "regex" => route.path = Some(PathMatch::Regex(Regex::new(value)?)),
+            "method" => {
+                route.methods = value
+                    .split('|')
+                    .map(|method| Method::from_str(&method.trim().to_uppercase()))
+                    .collect::<Result<_, _>>()?;
+            }
+            "header" => {
+                let (name, expected) = value
+                    .split_once(':')
+                    .map_or((value, None), |(name, expected)| {
+                        (name, Some(expected.trim().to_string()))
+                    });
+                route
+                    .headers
+                    .push((HeaderName::from_str(name.trim())?, expected));
+            }
+            other => anyhow::bail!(
+                "Unknown route condition '{other}', expected host, prefix, regex, method or header"
+            ),
+        }
+    }
+
+    Ok(route)
+}
+
+/// Lowercase host of a request without its port, from `Host` or the URI authority
+fn request_host(parts: &Parts) -> Option<String> {
+    let host = parts
+        .headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| parts.uri.host())?;
+
+    // IPv6 literals keep their brackets, so only a trailing `:port` is stripped
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => host,
+    };
+
+    Some(host.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_pool_settings() {
+        let config = pool_config(
+            &SystemConfig::for_test(),
+            "connect_timeout_ms:500, health_check_headers:Host: api.internal|X-Probe: 1, \
+             health_check_body_contains:healthy, health_check_jitter_ms:250, strip_prefix:/api",
+        )
+        .expect("pool config");
+
+        assert_eq!(config.upstream_connect_timeout_ms, 500);
+        assert_eq!(config.health_check_headers, "Host: api.internal;X-Probe: 1");
+        assert_eq!(
+            config.health_check_body_contains.as_deref(),
+            Some("healthy")
+        );
+        assert_eq!(config.health_check_body_regex, None);
+        assert_eq!(config.health_check_jitter_ms, 250);
+        assert_eq!(config.strip_prefix.as_deref(), Some("/api"));
+        assert!(HealthCheck::from_config(&config).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_pool_settings() {
+        assert!(pool_config(&SystemConfig::for_test(), "retries:3").is_err());
+    }
+}
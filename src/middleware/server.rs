@@ -1,10 +1,24 @@
-use std::str::FromStr as _;
+use std::{str::FromStr as _, sync::Arc, time::Duration};
 
-use axum::response::{IntoResponse, Response};
+use axum::{
+    body::Body,
+    http::{HeaderMap, header::CONTENT_LENGTH},
+    response::{IntoResponse, Response},
+};
+use futures_util::StreamExt as _;
 use reqwest::{Method, Response as ReqwestResponse, StatusCode, Url};
 use serde::{Deserialize, Serialize};
 
-use crate::{db::RedisClient, error::Error};
+use crate::{
+    algorithms::ServerResources,
+    db::RedisClient,
+    error::Error,
+    middleware::{
+        connection_guard::ConnectionGuard,
+        headers::{downstream_response_headers, upstream_request_headers},
+        timeouts::{TimeoutKind, TimeoutMetrics},
+    },
+};
 
 #[derive(Clone)]
 pub struct ServerClient {
@@ -13,90 +27,69 @@ pub struct ServerClient {
 }
 
 impl ServerClient {
-    /// Handles incoming requests and forwards them to the server
+    /// Handles incoming requests and forwards them to the server.
+    ///
+    /// The server's load is incremented while the request, including its streamed
+    /// response body, is in flight.
     pub async fn handle_request(
         &self,
         method: Method,
-        route: &str,
-        body: Option<serde_json::Value>,
-        mut redis_conn: RedisClient,
+        url: Url,
+        headers: HeaderMap,
+        body: Option<Body>,
+        redis_conn: RedisClient,
+        instance_id: Arc<str>,
     ) -> Result<ApiResponse, Error> {
-        let result = match method {
-            Method::GET => self.get_request(route, body).await,
-            Method::POST => self.post_request(route, body).await,
-            _ => return Err(Error::MethodNotAllowed),
-        };
-
-        // Update load once, regardless of success or failure
-        redis_conn.update_server_load(self.url.as_str(), 1).await?;
-
-        result
-    }
-
-    /// Sends a POST request to the server
-    pub async fn post_request(
-        &self,
-        route: &str,
-        body: Option<serde_json::Value>,
-    ) -> Result<ApiResponse, Error> {
-        let url = self.url.join(route).map_err(|_| Error::InvalidUrl)?;
+        let guard = ConnectionGuard::acquire(redis_conn, instance_id, self.url.as_str()).await?;
 
-        let post = self.client.post(url);
+        let response = self.send_request(method, url, headers, body).await?;
 
-        let response = if let Some(body) = body {
-            post.body(body.to_string())
-                .send()
-                .await
-                .map(ApiResponse::from_response)
-        } else {
-            post.send().await.map(ApiResponse::from_response)
-        };
-
-        response.map_err(|e| Error::Other(e.into()))?.await
+        Ok(response.hold_until_complete(guard))
     }
 
-    /// Sends a GET request to the server
-    pub async fn get_request(
+    /// Sends a request with the given method to an upstream URL on the server.
+    ///
+    /// Client headers are forwarded without hop-by-hop headers and the body is
+    /// streamed through byte-for-byte. `HEAD` requests are sent without a body.
+    /// The response body is streamed back to the client as it arrives.
+    pub async fn send_request(
         &self,
-        route: &str,
-        body: Option<serde_json::Value>,
+        method: Method,
+        url: Url,
+        headers: HeaderMap,
+        body: Option<Body>,
     ) -> Result<ApiResponse, Error> {
-        let url = self.url.join(route).map_err(|_| Error::InvalidUrl)?;
+        let is_head = method == Method::HEAD;
 
-        let get = self.client.get(url);
+        let mut headers = upstream_request_headers(headers);
+        if is_head {
+            // The body is dropped, so its framing must not be announced either
+            headers.remove(CONTENT_LENGTH);
+        }
 
-        let response = if let Some(body) = body {
-            get.body(body.to_string())
-                .send()
-                .await
-                .map(ApiResponse::from_response)
-        } else {
-            get.send().await.map(ApiResponse::from_response)
-        };
+        let mut request = self.client.request(method, url).headers(headers);
 
-        response.map_err(|e| Error::Other(e.into()))?.await
-    }
+        if let Some(body) = body.filter(|_| !is_head) {
+            request = request.body(reqwest::Body::wrap_stream(body.into_data_stream()));
+        }
 
-    /// Checks if the server is available by sending a request to the `/status` endpoint
-    pub async fn is_available(&self) -> bool {
-        if let Ok(url) = self.url.join("/status") {
-            match self.client.get(url).send().await {
-                Ok(response) => response.status().is_success(),
-                Err(_) => false,
+        let response = request.send().await.map_err(|e| {
+            if e.is_connect() && e.is_timeout() {
+                Error::UpstreamTimeout(TimeoutKind::Connect)
+            } else {
+                Error::Upstream(e)
             }
-        } else {
-            false
-        }
+        })?;
+
+        Ok(ApiResponse::from_response(response))
     }
 }
 
-impl From<StaticServerData> for ServerClient {
-    fn from(value: StaticServerData) -> Self {
-        Self {
-            url: value.url,
-            client: reqwest::Client::new(),
-        }
-    }
+/// Result of a health check probe against a server
+#[derive(Default)]
+pub struct StatusProbe {
+    pub available: bool,
+    pub resources: Option<ServerResources>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -131,20 +124,207 @@ impl StaticServerData {
 
 pub struct ApiResponse {
     status: StatusCode,
-    message: String,
+    headers: HeaderMap,
+    body: Body,
 }
 
 impl ApiResponse {
-    async fn from_response(response: ReqwestResponse) -> Result<Self, Error> {
+    fn from_response(response: ReqwestResponse) -> Self {
         let status = response.status();
-        let message = response.text().await.map_err(|_| Error::InvalidResponse)?;
+        let headers = downstream_response_headers(response.headers().clone());
+        let body = Body::from_stream(response.bytes_stream());
+
+        Self {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Ends the response body with an error when the backend stays silent for longer than `idle`
+    pub fn with_idle_timeout(self, idle: Duration, metrics: Arc<TimeoutMetrics>) -> Self {
+        let stream = futures_util::stream::unfold(
+            (self.body.into_data_stream(), false),
+            move |(mut stream, timed_out)| {
+                let metrics = metrics.clone();
+                async move {
+                    if timed_out {
+                        return None;
+                    }
+
+                    match tokio::time::timeout(idle, stream.next()).await {
+                        Ok(chunk) => chunk.map(|chunk| (chunk, (stream, false))),
+                        Err(_) => {
+                            tracing::warn!("Upstream response body idle for more than {:?}", idle);
+                            metrics.record(TimeoutKind::Idle);
 
-        Ok(Self { status, message })
+                            let error = axum::Error::new(Error::UpstreamTimeout(TimeoutKind::Idle));
+                            Some((Err(error), (stream, true)))
+                        }
+                    }
+                }
+            },
+        );
+
+        Self {
+            body: Body::from_stream(stream),
+            ..self
+        }
+    }
+
+    /// Keeps `value` alive until the response body has been fully sent or dropped
+    fn hold_until_complete<T: Send + 'static>(self, value: T) -> Self {
+        let stream = self.body.into_data_stream().map(move |chunk| {
+            let _ = &value;
+            chunk
+        });
+
+        Self {
+            body: Body::from_stream(stream),
+            ..self
+        }
     }
 }
 
 impl IntoResponse for ApiResponse {
     fn into_response(self) -> Response {
-        (self.status, self.message).into_response()
+        (self.status, self.headers, self.body).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{Router, body::to_bytes, extract::Request, http::Uri};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::middleware::{PathRewrite, upstream_url};
+
+    /// Method, path and body of a request received by the mock backend
+    type Received = Arc<Mutex<Vec<(Method, String, Vec<u8>)>>>;
+
+    /// Starts a backend that records every request and answers with a fixed body
+    async fn mock_backend() -> (ServerClient, Received) {
+        let received = Received::default();
+        let recorder = received.clone();
+
+        let app = Router::new().fallback(move |request: Request| {
+            let recorder = recorder.clone();
+            async move {
+                let (parts, body) = request.into_parts();
+                let body = to_bytes(body, usize::MAX).await.expect("read request body");
+                recorder.lock().expect("recorder lock").push((
+                    parts.method,
+                    parts.uri.path().to_string(),
+                    body.to_vec(),
+                ));
+                "backend body"
+            }
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local address");
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let server = ServerClient {
+            url: format!("http://{addr}/").parse().expect("backend url"),
+            client: reqwest::Client::new(),
+        };
+
+        (server, received)
+    }
+
+    async fn send(
+        server: &ServerClient,
+        method: Method,
+        path: &str,
+        body: &str,
+    ) -> (StatusCode, Vec<u8>) {
+        let uri = Uri::from_str(path).expect("request uri");
+        let url = upstream_url(&server.url, &uri, &PathRewrite::default()).expect("upstream url");
+
+        // Framed as a client would send it, so GET and DELETE bodies are delimited too
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, body.len().into());
+
+        let response = server
+            .send_request(method, url, headers, Some(Body::from(body.to_string())))
+            .await
+            .expect("send request");
+        let status = response.status();
+        let body = to_bytes(response.into_response().into_body(), usize::MAX)
+            .await
+            .expect("read response body");
+
+        (status, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn forwards_every_method_with_its_body() {
+        let (server, received) = mock_backend().await;
+
+        for method in [
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+            Method::OPTIONS,
+        ] {
+            let (status, body) = send(&server, method.clone(), "/items/1", "payload").await;
+
+            assert_eq!(status, StatusCode::OK, "{method}");
+            assert_eq!(body, b"backend body", "{method}");
+
+            let (upstream_method, path, upstream_body) = received
+                .lock()
+                .expect("recorder lock")
+                .pop()
+                .expect("request received");
+            assert_eq!(upstream_method, method);
+            assert_eq!(path, "/items/1");
+            assert_eq!(upstream_body, b"payload", "{method}");
+        }
+    }
+
+    #[tokio::test]
+    async fn head_sends_and_returns_no_body() {
+        let (server, received) = mock_backend().await;
+
+        let (status, body) = send(&server, Method::HEAD, "/items/1", "payload").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.is_empty());
+
+        let (method, _, upstream_body) = received
+            .lock()
+            .expect("recorder lock")
+            .pop()
+            .expect("request received");
+        assert_eq!(method, Method::HEAD);
+        assert!(upstream_body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn options_asterisk_goes_to_the_base_path() {
+        let (server, received) = mock_backend().await;
+
+        let (status, _) = send(&server, Method::OPTIONS, "*", "").await;
+
+        assert_eq!(status, StatusCode::OK);
+
+        let (method, path, _) = received
+            .lock()
+            .expect("recorder lock")
+            .pop()
+            .expect("request received");
+        assert_eq!(method, Method::OPTIONS);
+        assert_eq!(path, "/");
     }
 }
@@ -0,0 +1,184 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+/// Timeout hit while proxying a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    /// Establishing the connection to the backend
+    Connect,
+    /// A single attempt, until the response headers arrive
+    PerTry,
+    /// All attempts of a request, retries included, until the response headers arrive
+    Total,
+    /// Time between two chunks of the response body
+    Idle,
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TimeoutKind::Connect => "connect",
+            TimeoutKind::PerTry => "per-try",
+            TimeoutKind::Total => "total",
+            TimeoutKind::Idle => "idle",
+        })
+    }
+}
+
+/// Upstream timeouts applied to a request; `None` disables a timeout
+#[derive(Clone, Copy, Default)]
+pub struct UpstreamTimeouts {
+    pub connect: Option<Duration>,
+    pub per_try: Option<Duration>,
+    pub total: Option<Duration>,
+    pub idle: Option<Duration>,
+}
+
+impl UpstreamTimeouts {
+    /// Applies the `connect:ms,per_try:ms,total:ms,idle:ms` overrides, where `0` disables a timeout
+    fn with_overrides(mut self, overrides: &str) -> anyhow::Result<Self> {
+        for entry in overrides
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let (kind, ms) = entry.split_once(':').ok_or_else(|| {
+                anyhow::anyhow!("Invalid timeout '{entry}', expected 'kind:milliseconds'")
+            })?;
+            let timeout = timeout_from_millis(ms.trim().parse()?);
+
+            match kind.trim() {
+                "connect" => self.connect = timeout,
+                "per_try" => self.per_try = timeout,
+                "total" => self.total = timeout,
+                "idle" => self.idle = timeout,
+                other => {
+                    anyhow::bail!(
+                        "Unknown timeout '{other}', expected connect, per_try, total or idle"
+                    )
+                }
+            }
+        }
+
+        Ok(self)
+    }
+}
+
+/// Converts a configured number of milliseconds into a timeout, `0` meaning none
+pub fn timeout_from_millis(ms: u64) -> Option<Duration> {
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+/// Default upstream timeouts along with per-route overrides
+pub struct TimeoutTable {
+    default: UpstreamTimeouts,
+    /// Path prefixes and their timeouts, longest prefix first
+    routes: Vec<(String, UpstreamTimeouts)>,
+}
+
+impl TimeoutTable {
+    /// Parses the route overrides `/api=per_try:2000,total:5000;/upload=idle:120000`
+    pub fn parse(default: UpstreamTimeouts, routes: &str) -> anyhow::Result<Self> {
+        let mut routes = routes
+            .split(';')
+            .map(str::trim)
+            .filter(|route| !route.is_empty())
+            .map(|route| {
+                let (prefix, overrides) = route.split_once('=').ok_or_else(|| {
+                    anyhow::anyhow!("Invalid route timeouts '{route}', expected 'prefix=timeouts'")
+                })?;
+                Ok((
+                    prefix.trim().to_string(),
+                    default.with_overrides(overrides)?,
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        Ok(Self { default, routes })
+    }
+
+    /// Timeouts of requests no route override matches
+    pub fn defaults(&self) -> UpstreamTimeouts {
+        self.default
+    }
+
+    /// Timeouts for a request path
+    pub fn for_path(&self, path: &str) -> UpstreamTimeouts {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .map_or(self.default, |(_, timeouts)| *timeouts)
+    }
+}
+
+/// Number of upstream timeouts hit by this instance, per kind
+#[derive(Default)]
+pub struct TimeoutMetrics {
+    connect: AtomicU64,
+    per_try: AtomicU64,
+    total: AtomicU64,
+    idle: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct TimeoutCounts {
+    pub connect: u64,
+    pub per_try: u64,
+    pub total: u64,
+    pub idle: u64,
+}
+
+impl TimeoutMetrics {
+    pub fn record(&self, kind: TimeoutKind) {
+        let counter = match kind {
+            TimeoutKind::Connect => &self.connect,
+            TimeoutKind::PerTry => &self.per_try,
+            TimeoutKind::Total => &self.total,
+            TimeoutKind::Idle => &self.idle,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> TimeoutCounts {
+        TimeoutCounts {
+            connect: self.connect.load(Ordering::Relaxed),
+            per_try: self.per_try.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            idle: self.idle.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_the_connect_timeout_per_route() {
+        let default = UpstreamTimeouts {
+            connect: Some(Duration::from_secs(2)),
+            per_try: Some(Duration::from_secs(15)),
+            ..Default::default()
+        };
+        let table = TimeoutTable::parse(default, "/api=connect:500;/slow=connect:0")
+            .expect("route timeouts");
+
+        let api = table.for_path("/api/users");
+        assert_eq!(api.connect, Some(Duration::from_millis(500)));
+        assert_eq!(api.per_try, Some(Duration::from_secs(15)));
+
+        assert_eq!(table.for_path("/slow").connect, None);
+        assert_eq!(
+            table.for_path("/other").connect,
+            Some(Duration::from_secs(2))
+        );
+    }
+}
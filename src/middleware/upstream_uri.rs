@@ -0,0 +1,238 @@
+use axum::http::Uri;
+use reqwest::Url;
+
+use crate::error::Error;
+
+/// Path rewrite applied to requests before they are sent to a backend pool
+#[derive(Clone, Default)]
+pub struct PathRewrite {
+    /// Prefix removed from the request path, matched on a segment boundary
+    pub strip_prefix: Option<String>,
+    /// Prefix added to the request path after stripping
+    pub add_prefix: Option<String>,
+}
+
+impl PathRewrite {
+    pub fn new(strip_prefix: Option<String>, add_prefix: Option<String>) -> Self {
+        Self {
+            strip_prefix: strip_prefix.and_then(normalize_prefix),
+            add_prefix: add_prefix.and_then(normalize_prefix),
+        }
+    }
+
+    fn apply(&self, path: &str) -> String {
+        let stripped = match &self.strip_prefix {
+            Some(prefix) if path == prefix => "/",
+            Some(prefix) => match path.strip_prefix(prefix.as_str()) {
+                Some(rest) if rest.starts_with('/') => rest,
+                _ => path,
+            },
+            None => path,
+        };
+
+        match &self.add_prefix {
+            Some(prefix) => format!("{prefix}{stripped}"),
+            None => stripped.to_string(),
+        }
+    }
+}
+
+/// Builds the URL a request is forwarded to on the given backend.
+///
+/// The request path is appended to the backend's base path rather than resolved against
+/// it, so `http://backend/api` receives `/users` as `/api/users`. Dot segments are removed
+/// from the request path first so it cannot climb above the base path, while double
+/// slashes and percent-encoded bytes are kept as sent. The query string is copied verbatim.
+///
+/// The asterisk form of `OPTIONS *` cannot be expressed as a URL, so it is sent to the
+/// backend's base path, without any path rewrite, as `OPTIONS /` would be.
+pub fn upstream_url(base: &Url, uri: &Uri, rewrite: &PathRewrite) -> Result<Url, Error> {
+    if base.cannot_be_a_base() {
+        return Err(Error::InvalidUrl);
+    }
+
+    let path = if uri.path() == "*" {
+        "/".to_string()
+    } else {
+        rewrite.apply(&remove_dot_segments(uri.path()))
+    };
+
+    let mut url = base.clone();
+    url.set_path(&format!("{}{}", base.path().trim_end_matches('/'), path));
+    url.set_query(uri.query());
+    url.set_fragment(None);
+
+    Ok(url)
+}
+
+/// RFC 3986 §5.2.4 dot-segment removal, also matching percent-encoded dots
+fn remove_dot_segments(path: &str) -> String {
+    let segments = path.split('/').skip(1).collect::<Vec<_>>();
+    let last = segments.len().saturating_sub(1);
+
+    let mut output: Vec<&str> = Vec::new();
+
+    for (i, segment) in segments.into_iter().enumerate() {
+        let is_dot = is_single_dot(segment);
+        let is_dot_dot = is_double_dot(segment);
+
+        if is_dot_dot {
+            output.pop();
+        }
+
+        if is_dot || is_dot_dot {
+            // A trailing dot segment still refers to a directory
+            if i == last {
+                output.push("");
+            }
+        } else {
+            output.push(segment);
+        }
+    }
+
+    format!("/{}", output.join("/"))
+}
+
+fn is_single_dot(segment: &str) -> bool {
+    segment == "." || segment.eq_ignore_ascii_case("%2e")
+}
+
+fn is_double_dot(segment: &str) -> bool {
+    ["..", ".%2e", "%2e.", "%2e%2e"]
+        .iter()
+        .any(|dots| segment.eq_ignore_ascii_case(dots))
+}
+
+/// Normalizes a configured prefix to `/segment[/segment]` form, treating `/` as no prefix
+fn normalize_prefix(prefix: String) -> Option<String> {
+    let trimmed = prefix.trim().trim_matches('/');
+
+    if trimmed.is_empty() {
+        None
+    } else {
+        Some(format!("/{trimmed}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forward(base: &str, uri: &str, rewrite: &PathRewrite) -> String {
+        let base = base.parse::<Url>().expect("base url");
+        let uri = uri.parse::<Uri>().expect("request uri");
+
+        upstream_url(&base, &uri, rewrite)
+            .expect("upstream url")
+            .to_string()
+    }
+
+    fn rewrite(strip: &str, add: &str) -> PathRewrite {
+        PathRewrite::new(Some(strip.to_string()), Some(add.to_string()))
+    }
+
+    #[test]
+    fn removes_dot_segments() {
+        assert_eq!(remove_dot_segments("/a/b/../c"), "/a/c");
+        assert_eq!(remove_dot_segments("/a/./b"), "/a/b");
+        assert_eq!(remove_dot_segments("/../../etc/passwd"), "/etc/passwd");
+    }
+
+    #[test]
+    fn removes_percent_encoded_dot_segments() {
+        assert_eq!(remove_dot_segments("/a/%2e%2e/b"), "/b");
+        assert_eq!(remove_dot_segments("/a/%2E./b"), "/b");
+        assert_eq!(remove_dot_segments("/a/.%2e/b"), "/b");
+        assert_eq!(remove_dot_segments("/a/%2e/b"), "/a/b");
+    }
+
+    #[test]
+    fn keeps_a_trailing_dot_segment_as_a_directory() {
+        assert_eq!(remove_dot_segments("/a/b/."), "/a/b/");
+        assert_eq!(remove_dot_segments("/a/b/.."), "/a/");
+    }
+
+    #[test]
+    fn keeps_double_slashes() {
+        assert_eq!(
+            forward("http://backend/", "/a//b", &PathRewrite::default()),
+            "http://backend/a//b"
+        );
+    }
+
+    #[test]
+    fn keeps_percent_encoded_bytes() {
+        assert_eq!(
+            forward(
+                "http://backend/",
+                "/files/a%2Fb%20c",
+                &PathRewrite::default()
+            ),
+            "http://backend/files/a%2Fb%20c"
+        );
+    }
+
+    #[test]
+    fn keeps_the_query_verbatim() {
+        assert_eq!(
+            forward(
+                "http://backend/",
+                "/search?q=a+b&tag=%2F&empty=",
+                &PathRewrite::default()
+            ),
+            "http://backend/search?q=a+b&tag=%2F&empty="
+        );
+    }
+
+    #[test]
+    fn appends_to_the_base_path() {
+        assert_eq!(
+            forward("http://backend/api", "/users", &PathRewrite::default()),
+            "http://backend/api/users"
+        );
+        assert_eq!(
+            forward("http://backend/api/", "/../users", &PathRewrite::default()),
+            "http://backend/api/users"
+        );
+    }
+
+    #[test]
+    fn strips_prefixes_on_a_segment_boundary() {
+        let rewrite = rewrite("/api", "");
+
+        assert_eq!(
+            forward("http://backend/", "/api/users", &rewrite),
+            "http://backend/users"
+        );
+        assert_eq!(
+            forward("http://backend/", "/api", &rewrite),
+            "http://backend/"
+        );
+        assert_eq!(
+            forward("http://backend/", "/apiary", &rewrite),
+            "http://backend/apiary"
+        );
+    }
+
+    #[test]
+    fn adds_prefixes_after_stripping() {
+        let rewrite = rewrite("/api/", "v1");
+
+        assert_eq!(
+            forward("http://backend/", "/api/users", &rewrite),
+            "http://backend/v1/users"
+        );
+        assert_eq!(
+            forward("http://backend/", "/apiary", &rewrite),
+            "http://backend/v1/apiary"
+        );
+    }
+
+    #[test]
+    fn sends_options_asterisk_to_the_base_path() {
+        assert_eq!(
+            forward("http://backend/api", "*", &rewrite("/api", "/v1")),
+            "http://backend/api/"
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use axum::{
+    Json,
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+    middleware::Next,
+    response::{IntoResponse as _, Response},
+};
+use serde::Serialize;
+
+use crate::{
+    config::State as AppState,
+    middleware::{CircuitBreakerStatus, TimeoutCounts},
+};
+
+#[derive(Serialize)]
+pub struct Metrics {
+    pub upstream_timeouts: TimeoutCounts,
+}
+
+/// Circuit breaker state of every backend this instance has sent traffic to
+pub async fn circuit_breakers(State(state): State<AppState>) -> Json<Vec<CircuitBreakerStatus>> {
+    Json(state.circuit_breakers.statuses())
+}
+
+/// Counters of this balancer instance
+pub async fn metrics(State(state): State<AppState>) -> Json<Metrics> {
+    Json(Metrics {
+        upstream_timeouts: state.timeout_metrics.counts(),
+    })
+}
+
+/// Serves admin requests carrying the `ADMIN_TOKEN` bearer token.
+///
+/// The admin endpoints expose backend URLs, so they answer `404` when no token is
+/// configured and `401` when the token is missing or wrong.
+pub async fn require_admin_token(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    match admin_access(state.admin_token.as_deref(), req.headers()) {
+        Ok(()) => next.run(req).await,
+        Err(status) => status.into_response(),
+    }
+}
+
+fn admin_access(token: Option<&str>, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let token = token.ok_or(StatusCode::NOT_FOUND)?;
+
+    let authorized = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()));
+
+    authorized.then_some(()).ok_or(StatusCode::UNAUTHORIZED)
+}
+
+/// Compares two byte strings in time independent of where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            format!("Bearer {token}").parse().expect("header value"),
+        );
+        headers
+    }
+
+    #[test]
+    fn hides_admin_routes_without_a_configured_token() {
+        assert_eq!(
+            admin_access(None, &bearer("anything")),
+            Err(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(
+            admin_access(None, &HeaderMap::new()),
+            Err(StatusCode::NOT_FOUND)
+        );
+    }
+
+    #[test]
+    fn requires_the_configured_token() {
+        assert_eq!(admin_access(Some("secret"), &bearer("secret")), Ok(()));
+        assert_eq!(
+            admin_access(Some("secret"), &bearer("secreT")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            admin_access(Some("secret"), &bearer("secret2")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            admin_access(Some("secret"), &HeaderMap::new()),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+}
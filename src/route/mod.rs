@@ -1 +1,30 @@
+use axum::http::Method;
+
+pub mod admin;
 pub mod health;
+
+/// Paths served by the balancer itself rather than proxied to a backend
+pub const LOCAL_ROUTES: [&str; 3] = ["/status", "/admin/circuit-breakers", "/admin/metrics"];
+
+/// Whether a request targets one of the balancer's own endpoints; anything else,
+/// including `/admin/users` or `/statuses`, belongs to the backends
+pub fn is_local_route(method: &Method, path: &str) -> bool {
+    (*method == Method::GET || *method == Method::HEAD) && LOCAL_ROUTES.contains(&path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_only_the_exact_local_paths() {
+        assert!(is_local_route(&Method::GET, "/status"));
+        assert!(is_local_route(&Method::GET, "/admin/metrics"));
+        assert!(is_local_route(&Method::HEAD, "/admin/circuit-breakers"));
+
+        assert!(!is_local_route(&Method::GET, "/admin/users"));
+        assert!(!is_local_route(&Method::GET, "/administrator"));
+        assert!(!is_local_route(&Method::GET, "/statuses"));
+        assert!(!is_local_route(&Method::POST, "/admin/metrics"));
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use crate::db::RedisClient;
+
+/// How long an instance is considered alive after its last heartbeat
+pub const INSTANCE_HEARTBEAT_TTL_SECS: u64 = 30;
+
+/// Background worker that keeps this instance's heartbeat alive and releases the
+/// in-flight load left behind by instances whose heartbeat has expired
+pub async fn connection_reconciler_worker(redis_conn: RedisClient, instance_id: Arc<str>) {
+    loop {
+        reconcile(redis_conn.clone(), &instance_id).await;
+        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+    }
+}
+
+async fn reconcile(mut redis_conn: RedisClient, instance_id: &str) {
+    if let Err(e) = redis_conn
+        .register_instance(instance_id, INSTANCE_HEARTBEAT_TTL_SECS)
+        .await
+    {
+        tracing::warn!("Failed to refresh instance heartbeat: {}", e);
+        return;
+    }
+
+    let Ok(instances) = redis_conn.get_all_instances().await else {
+        return;
+    };
+
+    for instance in instances.iter().filter(|i| i.as_str() != instance_id) {
+        if let Ok(false) = redis_conn.is_instance_alive(instance).await
+            && let Ok(true) = redis_conn.release_instance_load(instance).await
+        {
+            tracing::info!("Released in-flight load of dead instance {}", instance);
+        }
+    }
+
+    _ = redis_conn.clamp_server_load().await;
+}
@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+
+/// Health of a backend as seen by the status probes
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    /// In rotation
+    #[default]
+    Healthy,
+    /// Out of rotation after `failure_threshold` consecutive failed probes
+    Unhealthy,
+    /// Out of rotation until `success_threshold` consecutive probes succeed
+    Recovering,
+}
+
+/// Number of consecutive probe results needed to change a backend's health
+#[derive(Clone, Copy)]
+pub struct HealthThresholds {
+    pub failure_threshold: u32,
+    pub success_threshold: u32,
+}
+
+/// Health state of a backend along with the probe streak that leads to its next transition
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct ServerHealth {
+    pub state: HealthState,
+    pub consecutive_failures: u32,
+    pub consecutive_successes: u32,
+}
+
+impl ServerHealth {
+    /// Whether the backend may receive traffic
+    pub fn is_routable(&self) -> bool {
+        self.state == HealthState::Healthy
+    }
+
+    /// Applies the result of a probe and returns the new health
+    pub fn record_probe(self, success: bool, thresholds: &HealthThresholds) -> Self {
+        let (consecutive_failures, consecutive_successes) = if success {
+            (0, self.consecutive_successes.saturating_add(1))
+        } else {
+            (self.consecutive_failures.saturating_add(1), 0)
+        };
+
+        let state = match (self.state, success) {
+            (HealthState::Healthy, false)
+                if consecutive_failures >= thresholds.failure_threshold =>
+            {
+                HealthState::Unhealthy
+            }
+            (HealthState::Unhealthy | HealthState::Recovering, true)
+                if consecutive_successes >= thresholds.success_threshold =>
+            {
+                HealthState::Healthy
+            }
+            (HealthState::Unhealthy, true) => HealthState::Recovering,
+            (HealthState::Recovering, false) => HealthState::Unhealthy,
+            (state, _) => state,
+        };
+
+        Self {
+            state,
+            consecutive_failures,
+            consecutive_successes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: HealthThresholds = HealthThresholds {
+        failure_threshold: 3,
+        success_threshold: 2,
+    };
+
+    fn probe(health: ServerHealth, results: &[bool]) -> ServerHealth {
+        results.iter().fold(health, |health, success| {
+            health.record_probe(*success, &THRESHOLDS)
+        })
+    }
+
+    fn unhealthy() -> ServerHealth {
+        probe(ServerHealth::default(), &[false, false, false])
+    }
+
+    #[test]
+    fn falls_after_the_failure_threshold() {
+        let health = probe(ServerHealth::default(), &[false, false]);
+        assert_eq!(health.state, HealthState::Healthy);
+        assert!(health.is_routable());
+
+        let health = probe(health, &[false]);
+        assert_eq!(health.state, HealthState::Unhealthy);
+        assert!(!health.is_routable());
+    }
+
+    #[test]
+    fn rises_after_the_success_threshold() {
+        let health = probe(unhealthy(), &[true]);
+        assert_eq!(health.state, HealthState::Recovering);
+        assert!(!health.is_routable());
+
+        let health = probe(health, &[true]);
+        assert_eq!(health.state, HealthState::Healthy);
+        assert!(health.is_routable());
+    }
+
+    #[test]
+    fn resets_the_opposite_streak_when_the_result_flips() {
+        let health = probe(ServerHealth::default(), &[false, false, true]);
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.consecutive_successes, 1);
+
+        let health = probe(health, &[false]);
+        assert_eq!(health.consecutive_failures, 1);
+        assert_eq!(health.consecutive_successes, 0);
+    }
+
+    #[test]
+    fn needs_consecutive_failures_to_fall() {
+        let health = probe(
+            ServerHealth::default(),
+            &[false, false, true, false, false, true, false, false],
+        );
+
+        assert_eq!(health.state, HealthState::Healthy);
+    }
+
+    #[test]
+    fn a_failure_while_recovering_starts_over() {
+        let health = probe(unhealthy(), &[true, false]);
+        assert_eq!(health.state, HealthState::Unhealthy);
+
+        let health = probe(health, &[true]);
+        assert_eq!(health.state, HealthState::Recovering);
+
+        let health = probe(health, &[true]);
+        assert_eq!(health.state, HealthState::Healthy);
+    }
+
+    #[test]
+    fn stays_unhealthy_while_probes_keep_failing() {
+        let health = probe(unhealthy(), &[false, false, false, false]);
+
+        assert_eq!(health.state, HealthState::Unhealthy);
+        assert_eq!(health.consecutive_failures, 7);
+    }
+
+    #[test]
+    fn falls_again_after_recovering() {
+        let health = probe(unhealthy(), &[true, true, false, false, false]);
+
+        assert_eq!(health.state, HealthState::Unhealthy);
+    }
+}
@@ -0,0 +1,292 @@
+use std::{ops::RangeInclusive, str::FromStr as _, time::Duration};
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use rand::Rng as _;
+use regex::Regex;
+use reqwest::Method;
+
+use crate::{
+    config::SystemConfig,
+    middleware::{ServerClient, StatusProbe},
+};
+
+/// Largest response body read when matching a health check body
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// How a backend is probed
+#[derive(Clone)]
+pub enum HealthCheckKind {
+    /// An HTTP request whose response must match the expected status and body
+    Http(Box<HttpCheck>),
+    /// A TCP connection to the backend's host and port
+    Tcp,
+}
+
+#[derive(Clone)]
+pub struct HttpCheck {
+    pub method: Method,
+    pub path: String,
+    pub headers: HeaderMap,
+    /// Status codes counted as healthy
+    pub expected_status: Vec<RangeInclusive<u16>>,
+    pub body_match: Option<BodyMatch>,
+}
+
+/// Condition the health check response body must satisfy
+#[derive(Clone)]
+pub enum BodyMatch {
+    Contains(String),
+    Regex(Regex),
+}
+
+impl BodyMatch {
+    fn is_match(&self, body: &str) -> bool {
+        match self {
+            BodyMatch::Contains(needle) => body.contains(needle.as_str()),
+            BodyMatch::Regex(regex) => regex.is_match(body),
+        }
+    }
+}
+
+/// Active health check definition for a backend pool
+#[derive(Clone)]
+pub struct HealthCheck {
+    pub kind: HealthCheckKind,
+    /// Time allowed for a single probe
+    pub timeout: Duration,
+    /// Time between probe rounds
+    pub interval: Duration,
+    /// Maximum random delay added to each interval so instances do not probe in lockstep
+    pub jitter: Duration,
+}
+
+impl HealthCheck {
+    pub fn from_config(config: &SystemConfig) -> anyhow::Result<Self> {
+        let kind = match config.health_check_type.as_str() {
+            "http" => HealthCheckKind::Http(Box::new(HttpCheck {
+                method: Method::from_str(&config.health_check_method.to_uppercase())?,
+                path: config.health_check_path.clone(),
+                headers: parse_headers(&config.health_check_headers)?,
+                expected_status: parse_status_ranges(&config.health_check_expected_status)?,
+                body_match: match (
+                    &config.health_check_body_contains,
+                    &config.health_check_body_regex,
+                ) {
+                    (Some(_), Some(_)) => anyhow::bail!(
+                        "Only one of HEALTH_CHECK_BODY_CONTAINS and HEALTH_CHECK_BODY_REGEX can be set"
+                    ),
+                    (Some(needle), None) => Some(BodyMatch::Contains(needle.clone())),
+                    (None, Some(regex)) => Some(BodyMatch::Regex(Regex::new(regex)?)),
+                    (None, None) => None,
+                },
+            })),
+            "tcp" => HealthCheckKind::Tcp,
+            other => anyhow::bail!("Unknown health check type '{other}', expected http or tcp"),
+        };
+
+        let timeout = Duration::from_millis(config.health_check_timeout_ms);
+        let interval = Duration::from_secs(config.health_check_interval_secs);
+        if interval.is_zero() {
+            anyhow::bail!("HEALTH_CHECK_INTERVAL_SECS must be greater than 0");
+        }
+        if timeout >= interval {
+            anyhow::bail!(
+                "HEALTH_CHECK_TIMEOUT_MS ({}) must be shorter than HEALTH_CHECK_INTERVAL_SECS ({})",
+                config.health_check_timeout_ms,
+                config.health_check_interval_secs
+            );
+        }
+
+        Ok(Self {
+            kind,
+            timeout,
+            interval,
+            jitter: Duration::from_millis(config.health_check_jitter_ms),
+        })
+    }
+
+    /// Interval until the next probe round, including a random jitter
+    pub fn next_delay(&self) -> Duration {
+        let jitter_ms = u64::try_from(self.jitter.as_millis()).unwrap_or(u64::MAX);
+
+        if jitter_ms == 0 {
+            self.interval
+        } else {
+            self.interval + Duration::from_millis(rand::rng().random_range(0..=jitter_ms))
+        }
+    }
+
+    /// Probes a server, failing it if the probe does not complete within the timeout
+    pub async fn probe(&self, server: &ServerClient) -> StatusProbe {
+        tokio::time::timeout(self.timeout, self.run(server))
+            .await
+            .unwrap_or_default()
+    }
+
+    async fn run(&self, server: &ServerClient) -> StatusProbe {
+        match &self.kind {
+            HealthCheckKind::Tcp => {
+                // IPv6 literals keep their brackets in `host_str` and would not resolve
+                let Some(host) = server
+                    .url
+                    .host_str()
+                    .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+                else {
+                    return StatusProbe::default();
+                };
+                let Some(port) = server.url.port_or_known_default() else {
+                    return StatusProbe::default();
+                };
+
+                StatusProbe {
+                    available: tokio::net::TcpStream::connect((host, port)).await.is_ok(),
+                    resources: None,
+                }
+            }
+            HealthCheckKind::Http(check) => check.probe(server).await,
+        }
+    }
+}
+
+impl HttpCheck {
+    async fn probe(&self, server: &ServerClient) -> StatusProbe {
+        let Ok(url) = server.url.join(&self.path) else {
+            return StatusProbe::default();
+        };
+
+        let Ok(mut response) = server
+            .client
+            .request(self.method.clone(), url)
+            .headers(self.headers.clone())
+            .send()
+            .await
+        else {
+            return StatusProbe::default();
+        };
+
+        let status = response.status().as_u16();
+        if !self
+            .expected_status
+            .iter()
+            .any(|range| range.contains(&status))
+        {
+            return StatusProbe::default();
+        }
+
+        let mut body = Vec::new();
+        while body.len() < MAX_BODY_BYTES {
+            match response.chunk().await {
+                Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+                Ok(None) => break,
+                Err(_) => return StatusProbe::default(),
+            }
+        }
+
+        let available = self
+            .body_match
+            .as_ref()
+            .is_none_or(|body_match| body_match.is_match(&String::from_utf8_lossy(&body)));
+
+        StatusProbe {
+            available,
+            resources: serde_json::from_slice(&body).ok(),
+        }
+    }
+}
+
+/// Parses `Name: value;Name: value`
+fn parse_headers(value: &str) -> anyhow::Result<HeaderMap> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|header| !header.is_empty())
+        .map(|header| {
+            let (name, value) = header.split_once(':').ok_or_else(|| {
+                anyhow::anyhow!("Invalid health check header '{header}', expected 'Name: value'")
+            })?;
+            Ok((
+                HeaderName::from_str(name.trim())?,
+                HeaderValue::from_str(value.trim())?,
+            ))
+        })
+        .collect()
+}
+
+/// Parses `200-299,304` into inclusive status ranges
+fn parse_status_ranges(value: &str) -> anyhow::Result<Vec<RangeInclusive<u16>>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|range| !range.is_empty())
+        .map(|range| match range.split_once('-') {
+            Some((start, end)) => Ok(start.trim().parse()?..=end.trim().parse()?),
+            None => {
+                let status = range.parse()?;
+                Ok(status..=status)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn tcp_check() -> HealthCheck {
+        HealthCheck {
+            kind: HealthCheckKind::Tcp,
+            timeout: Duration::from_secs(1),
+            interval: Duration::from_secs(1),
+            jitter: Duration::ZERO,
+        }
+    }
+
+    async fn probe_listener(addr: &str) -> bool {
+        let listener = TcpListener::bind(addr).await.expect("bind");
+        let addr = listener.local_addr().expect("local address");
+        let server = ServerClient {
+            url: format!("http://{addr}/").parse().expect("backend url"),
+            client: reqwest::Client::new(),
+        };
+
+        tcp_check().run(&server).await.available
+    }
+
+    fn config(interval_secs: u64, timeout_ms: u64) -> SystemConfig {
+        SystemConfig {
+            health_check_interval_secs: interval_secs,
+            health_check_timeout_ms: timeout_ms,
+            ..SystemConfig::for_test()
+        }
+    }
+
+    #[test]
+    fn accepts_a_timeout_shorter_than_the_interval() {
+        let check = HealthCheck::from_config(&config(10, 2000)).expect("valid health check");
+        assert_eq!(check.interval, Duration::from_secs(10));
+        assert_eq!(check.timeout, Duration::from_millis(2000));
+    }
+
+    #[test]
+    fn rejects_a_zero_interval() {
+        assert!(HealthCheck::from_config(&config(0, 0)).is_err());
+    }
+
+    #[test]
+    fn rejects_a_timeout_not_shorter_than_the_interval() {
+        assert!(HealthCheck::from_config(&config(2, 2000)).is_err());
+        assert!(HealthCheck::from_config(&config(2, 5000)).is_err());
+    }
+
+    #[tokio::test]
+    async fn tcp_check_connects_to_ipv4_hosts() {
+        assert!(probe_listener("127.0.0.1:0").await);
+    }
+
+    #[tokio::test]
+    async fn tcp_check_connects_to_ipv6_hosts() {
+        assert!(probe_listener("[::1]:0").await);
+    }
+}
@@ -1,27 +1,52 @@
-use crate::db::RedisClient;
+use crate::{config::LatencyTracking, db::RedisClient};
+
+/// Background worker that recomputes each server's mean latency from its sample window.
+///
+/// Servers without samples in the window, because they were never used or their samples
+/// aged out, are no longer tracked rather than reported as instantly fast.
+///
+/// In `Ewma` mode the mean is updated as samples are recorded, so there is nothing to do.
+pub async fn latency_tracker_worker(redis_conn: RedisClient, latency_tracking: LatencyTracking) {
+    let LatencyTracking::Window { max_age, .. } = latency_tracking else {
+        return std::future::pending().await;
+    };
 
-pub async fn latency_tracker_worker(redis_conn: RedisClient) {
     loop {
-        check(redis_conn.clone()).await;
+        check(redis_conn.clone(), max_age).await;
         tokio::time::sleep(std::time::Duration::from_millis(100)).await;
     }
 }
 
-async fn check(mut redis_conn: RedisClient) {
-    if let Ok(data) = redis_conn.get_servers_latency_record().await {
+async fn check(mut redis_conn: RedisClient, max_age: Option<std::time::Duration>) {
+    if let Ok(data) = redis_conn.get_servers_latency_record(max_age).await {
         for (url, latencies) in data {
-            let mean_latency = mean_latency(latencies);
-            _ = redis_conn
-                .update_server_mean_latency(&url, mean_latency)
-                .await;
+            _ = match mean_latency(&latencies) {
+                Some(mean_latency) => {
+                    redis_conn
+                        .update_server_mean_latency(&url, mean_latency)
+                        .await
+                }
+                None => redis_conn.remove_server_mean_latency(&url).await,
+            };
         }
     }
 }
 
-fn mean_latency(latencies: Vec<u128>) -> u128 {
-    if latencies.is_empty() {
-        0
-    } else {
-        latencies.iter().sum::<u128>() / latencies.len() as u128
+fn mean_latency(latencies: &[u128]) -> Option<u128> {
+    (!latencies.is_empty()).then(|| latencies.iter().sum::<u128>() / latencies.len() as u128)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_the_samples() {
+        assert_eq!(mean_latency(&[10, 20, 45]), Some(25));
+    }
+
+    #[test]
+    fn leaves_servers_without_samples_untracked() {
+        assert_eq!(mean_latency(&[]), None);
     }
 }
@@ -1,5 +1,15 @@
+mod connection_reconciler_worker;
+mod health;
+mod health_check;
 mod latency_tracker_worker;
+mod outlier_detection;
+mod outlier_detection_worker;
 mod server_status_worker;
 
+pub use connection_reconciler_worker::connection_reconciler_worker;
+pub use health::{HealthThresholds, ServerHealth};
+pub use health_check::HealthCheck;
 pub use latency_tracker_worker::latency_tracker_worker;
+pub use outlier_detection::{OutlierDetection, OutlierDetector, RequestOutcome};
+pub use outlier_detection_worker::outlier_detection_worker;
 pub use server_status_worker::server_status_worker;
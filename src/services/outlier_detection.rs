@@ -0,0 +1,371 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use reqwest::StatusCode;
+
+use crate::{db::RedisClient, error::Error};
+
+/// Outlier detection settings, modelled on Envoy's outlier detection
+#[derive(Clone, Copy)]
+pub struct OutlierDetection {
+    /// Consecutive 5xx responses (or connection failures) before a server is ejected
+    pub consecutive_5xx: u32,
+    /// Consecutive 502/503/504 responses (or connection failures) before a server is ejected
+    pub consecutive_gateway_failure: u32,
+    /// Ejection time, multiplied by the number of times the server has been ejected
+    pub base_ejection_time: Duration,
+    /// Upper bound on a single ejection
+    pub max_ejection_time: Duration,
+    /// Maximum share of a pool's servers that can be ejected at once, in percent
+    pub max_ejection_percent: u32,
+    /// How often success rates are evaluated
+    pub interval: Duration,
+    /// Minimum number of servers with enough traffic to evaluate success rates
+    pub success_rate_minimum_hosts: usize,
+    /// Minimum number of requests in an interval for a server's success rate to count
+    pub success_rate_request_volume: u64,
+    /// Servers below `mean - stdev_factor * stdev` of the success rates are ejected
+    pub success_rate_stdev_factor: f64,
+}
+
+/// Outcome of a proxied request as seen by outlier detection
+#[derive(Clone, Copy)]
+pub enum RequestOutcome {
+    /// The backend answered with this status
+    Response(StatusCode),
+    /// The request never got a response (connection error, reset or timeout)
+    GatewayError,
+}
+
+impl RequestOutcome {
+    fn is_5xx(self) -> bool {
+        match self {
+            RequestOutcome::Response(status) => status.is_server_error(),
+            RequestOutcome::GatewayError => true,
+        }
+    }
+
+    fn is_gateway_failure(self) -> bool {
+        match self {
+            RequestOutcome::Response(status) => matches!(
+                status,
+                StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ),
+            RequestOutcome::GatewayError => true,
+        }
+    }
+}
+
+#[derive(Default)]
+struct HostStats {
+    consecutive_5xx: u32,
+    consecutive_gateway_failures: u32,
+    /// Number of times the server has been ejected; decays while it stays healthy
+    ejection_count: u32,
+    ejected_until: Option<Instant>,
+    successes: u64,
+    requests: u64,
+}
+
+impl HostStats {
+    fn reset_consecutive_failures(&mut self) {
+        self.consecutive_5xx = 0;
+        self.consecutive_gateway_failures = 0;
+    }
+}
+
+/// Tracks live traffic per server and ejects outliers from rotation.
+///
+/// Counters are kept per balancer instance, like Envoy, while ejections are stored
+/// in Redis so every instance stops routing to an ejected server.
+pub struct OutlierDetector {
+    config: OutlierDetection,
+    /// Servers of each upstream pool, which the max ejection percentage applies to
+    pools: Vec<HashSet<String>>,
+    hosts: Mutex<HashMap<String, HostStats>>,
+}
+
+impl OutlierDetector {
+    pub fn new(config: OutlierDetection, pools: Vec<HashSet<String>>) -> Self {
+        Self {
+            config,
+            pools,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.config.interval
+    }
+
+    /// Records the outcome of a request, ejecting the server if it crossed a threshold
+    pub async fn record(&self, redis_conn: &mut RedisClient, url: &str, outcome: RequestOutcome) {
+        let ejection = {
+            let Ok(mut hosts) = self.hosts.lock() else {
+                return;
+            };
+            let stats = hosts.entry(url.to_string()).or_default();
+
+            stats.requests += 1;
+
+            if outcome.is_5xx() {
+                stats.consecutive_5xx += 1;
+            } else {
+                stats.consecutive_5xx = 0;
+                stats.successes += 1;
+            }
+
+            if outcome.is_gateway_failure() {
+                stats.consecutive_gateway_failures += 1;
+            } else {
+                stats.consecutive_gateway_failures = 0;
+            }
+
+            if stats.consecutive_5xx >= self.config.consecutive_5xx {
+                Some("consecutive 5xx")
+            } else if stats.consecutive_gateway_failures >= self.config.consecutive_gateway_failure
+            {
+                Some("consecutive gateway failures")
+            } else {
+                None
+            }
+        };
+
+        if let Some(reason) = ejection {
+            self.eject(redis_conn, url, reason).await;
+        }
+    }
+
+    /// Ejects success rate outliers and resets the per-interval counters
+    pub async fn evaluate(&self, redis_conn: &mut RedisClient) {
+        let outliers = {
+            let Ok(mut hosts) = self.hosts.lock() else {
+                return;
+            };
+
+            let rates = hosts
+                .iter()
+                .filter(|(_, stats)| stats.requests >= self.config.success_rate_request_volume)
+                .map(|(url, stats)| (url.clone(), stats.successes as f64 / stats.requests as f64))
+                .collect::<Vec<_>>();
+
+            let outliers = success_rate_outliers(&rates, &self.config);
+
+            let now = Instant::now();
+
+            for stats in hosts.values_mut() {
+                if stats.ejected_until.is_none_or(|until| until <= now) {
+                    stats.ejection_count = stats.ejection_count.saturating_sub(1);
+                }
+                stats.successes = 0;
+                stats.requests = 0;
+            }
+
+            outliers
+        };
+
+        for url in outliers {
+            self.eject(redis_conn, &url, "low success rate").await;
+        }
+    }
+
+    async fn eject(&self, redis_conn: &mut RedisClient, url: &str, reason: &str) {
+        if let Err(e) = self.try_eject(redis_conn, url, reason).await {
+            tracing::warn!("Failed to eject {}: {}", url, e);
+        }
+    }
+
+    async fn try_eject(
+        &self,
+        redis_conn: &mut RedisClient,
+        url: &str,
+        reason: &str,
+    ) -> Result<(), Error> {
+        let ejected = redis_conn.get_ejected_servers().await?;
+        if ejected.contains(url) {
+            return Ok(());
+        }
+
+        if exceeds_max_ejection(&self.pools, &ejected, url, self.config.max_ejection_percent) {
+            self.refuse_ejection(url);
+            tracing::warn!(
+                "Not ejecting {} ({}): max ejection percentage reached",
+                url,
+                reason
+            );
+            return Ok(());
+        }
+
+        let ejection_time = {
+            let Ok(mut hosts) = self.hosts.lock() else {
+                return Ok(());
+            };
+            let stats = hosts.entry(url.to_string()).or_default();
+
+            stats.ejection_count += 1;
+            stats.reset_consecutive_failures();
+
+            let ejection_time = self
+                .config
+                .base_ejection_time
+                .saturating_mul(stats.ejection_count)
+                .min(self.config.max_ejection_time);
+
+            stats.ejected_until = Some(Instant::now() + ejection_time);
+            ejection_time
+        };
+
+        redis_conn.eject_server(url, ejection_time).await?;
+
+        tracing::warn!("Ejected {} for {:?} ({})", url, ejection_time, reason);
+
+        Ok(())
+    }
+
+    /// Starts the consecutive failure counts over for a server the ejection cap kept in
+    /// rotation, so it is only reconsidered after another full run of failures instead of
+    /// on every failed request
+    fn refuse_ejection(&self, url: &str) {
+        if let Ok(mut hosts) = self.hosts.lock()
+            && let Some(stats) = hosts.get_mut(url)
+        {
+            stats.reset_consecutive_failures();
+        }
+    }
+}
+
+/// Whether ejecting `url` would take any pool it belongs to over `max_percent` ejected
+/// servers. Like Envoy, one server per pool can always be ejected regardless of the
+/// percentage.
+fn exceeds_max_ejection(
+    pools: &[HashSet<String>],
+    ejected: &HashSet<String>,
+    url: &str,
+    max_percent: u32,
+) -> bool {
+    pools.iter().filter(|pool| pool.contains(url)).any(|pool| {
+        let ejected = pool
+            .iter()
+            .filter(|server| ejected.contains(*server))
+            .count();
+        ejected > 0 && (ejected + 1) * 100 > pool.len() * max_percent as usize
+    })
+}
+
+fn success_rate_outliers(rates: &[(String, f64)], config: &OutlierDetection) -> Vec<String> {
+    if rates.is_empty() || rates.len() < config.success_rate_minimum_hosts {
+        return Vec::new();
+    }
+
+    let count = rates.len() as f64;
+    let mean = rates.iter().map(|(_, rate)| rate).sum::<f64>() / count;
+    let variance = rates
+        .iter()
+        .map(|(_, rate)| (rate - mean).powi(2))
+        .sum::<f64>()
+        / count;
+    let threshold = mean - config.success_rate_stdev_factor * variance.sqrt();
+
+    rates
+        .iter()
+        .filter(|(_, rate)| *rate < threshold)
+        .map(|(url, _)| url.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servers(urls: &[&str]) -> HashSet<String> {
+        urls.iter().map(|url| url.to_string()).collect()
+    }
+
+    fn detector() -> OutlierDetector {
+        OutlierDetector::new(
+            OutlierDetection {
+                consecutive_5xx: 5,
+                consecutive_gateway_failure: 5,
+                base_ejection_time: Duration::from_secs(30),
+                max_ejection_time: Duration::from_secs(300),
+                max_ejection_percent: 10,
+                interval: Duration::from_secs(10),
+                success_rate_minimum_hosts: 5,
+                success_rate_request_volume: 100,
+                success_rate_stdev_factor: 1.9,
+            },
+            vec![servers(&["a1", "a2"])],
+        )
+    }
+
+    #[test]
+    fn refused_ejections_reset_consecutive_failures() {
+        let detector = detector();
+        {
+            let mut hosts = detector.hosts.lock().expect("hosts lock");
+            let stats = hosts.entry("a1".to_string()).or_default();
+            stats.consecutive_5xx = 5;
+            stats.consecutive_gateway_failures = 5;
+            stats.requests = 10;
+        }
+
+        detector.refuse_ejection("a1");
+
+        let hosts = detector.hosts.lock().expect("hosts lock");
+        let stats = &hosts["a1"];
+        assert_eq!(stats.consecutive_5xx, 0);
+        assert_eq!(stats.consecutive_gateway_failures, 0);
+        assert_eq!(stats.requests, 10);
+        assert_eq!(stats.ejection_count, 0);
+    }
+
+    #[test]
+    fn limits_ejections_per_pool() {
+        let pools = [
+            servers(&["a1", "a2", "a3", "a4"]),
+            servers(&["b1", "b2", "b3", "b4", "b5", "b6", "b7", "b8"]),
+        ];
+
+        // Ejections in another pool do not count against this one
+        let ejected = servers(&["b1", "b2", "b3"]);
+        assert!(!exceeds_max_ejection(&pools, &ejected, "a1", 50));
+
+        let ejected = servers(&["a1", "a2"]);
+        assert!(exceeds_max_ejection(&pools, &ejected, "a3", 50));
+        assert!(!exceeds_max_ejection(&pools, &ejected, "b1", 50));
+    }
+
+    #[test]
+    fn always_allows_one_ejection_per_pool() {
+        let pools = [servers(&["a1", "a2", "a3", "a4"])];
+
+        assert!(!exceeds_max_ejection(&pools, &HashSet::new(), "a1", 10));
+        assert!(exceeds_max_ejection(&pools, &servers(&["a1"]), "a2", 10));
+    }
+
+    #[test]
+    fn applies_every_pool_a_shared_server_belongs_to() {
+        let pools = [
+            servers(&["shared", "a1"]),
+            servers(&["shared", "b1", "b2", "b3"]),
+        ];
+
+        assert!(exceeds_max_ejection(
+            &pools,
+            &servers(&["a1"]),
+            "shared",
+            50
+        ));
+        assert!(!exceeds_max_ejection(
+            &pools,
+            &servers(&["b1"]),
+            "shared",
+            50
+        ));
+    }
+}
@@ -0,0 +1,11 @@
+use std::sync::Arc;
+
+use crate::{db::RedisClient, services::OutlierDetector};
+
+/// Background worker that periodically ejects success rate outliers
+pub async fn outlier_detection_worker(mut redis_conn: RedisClient, detector: Arc<OutlierDetector>) {
+    loop {
+        tokio::time::sleep(detector.interval()).await;
+        detector.evaluate(&mut redis_conn).await;
+    }
+}
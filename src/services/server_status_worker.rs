@@ -1,24 +1,68 @@
-use crate::db::RedisClient;
+use std::sync::Arc;
 
-/// Background worker that periodically checks the status of available servers
-pub async fn server_status_worker(redis_conn: RedisClient) {
+use crate::{
+    db::RedisClient,
+    error::Error,
+    middleware::{ClientRegistry, StatusProbe, UpstreamPool},
+    services::HealthThresholds,
+};
+
+/// Attempts at applying a probe while other instances keep updating the same server
+const MAX_HEALTH_UPDATE_ATTEMPTS: usize = 5;
+
+/// Background worker that periodically checks the status of a pool's servers with the
+/// pool's health check and moves them in and out of rotation
+pub async fn server_status_worker(
+    redis_conn: RedisClient,
+    pool: Arc<UpstreamPool>,
+    thresholds: HealthThresholds,
+    clients: Arc<ClientRegistry>,
+) {
     loop {
-        if let Err(failing_servers) = server_status(redis_conn.clone()).await {
-            // TODO: remove them from the list of available servers
-            tracing::warn!("Failing servers: {:#?}", failing_servers);
+        if let Err(failing_servers) =
+            server_status(redis_conn.clone(), &pool, &thresholds, &clients).await
+        {
+            tracing::warn!(
+                "Failing servers in pool {}: {:#?}",
+                pool.name,
+                failing_servers
+            );
         }
-        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        tokio::time::sleep(pool.health_check.next_delay()).await;
     }
 }
 
-async fn server_status(mut redis_conn: RedisClient) -> Result<(), Vec<String>> {
+async fn server_status(
+    mut redis_conn: RedisClient,
+    pool: &UpstreamPool,
+    thresholds: &HealthThresholds,
+    clients: &ClientRegistry,
+) -> Result<(), Vec<String>> {
     let mut failing_servers = Vec::new();
 
-    if let Ok(data) = redis_conn.get_all_server_url().await {
-        for server in data {
-            if !server.is_available().await {
-                failing_servers.push(server.url.to_string());
-            }
+    for server in &pool.servers {
+        let probe =
+            match clients.server_client(server.url.clone(), pool.timeouts.defaults().connect) {
+                Ok(server) => pool.health_check.probe(&server).await,
+                Err(_) => StatusProbe::default(),
+            };
+        let url = server.url.as_str();
+
+        let stored = match probe.resources {
+            Some(resources) => redis_conn.update_server_resources(url, &resources).await,
+            None => redis_conn.remove_server_resources(url).await,
+        };
+
+        if let Err(e) = stored {
+            tracing::warn!("Failed to store resources of {}: {}", url, e);
+        }
+
+        if let Err(e) = update_health(&mut redis_conn, url, probe.available, thresholds).await {
+            tracing::warn!("Failed to update health of {}: {}", url, e);
+        }
+
+        if !probe.available {
+            failing_servers.push(url.to_string());
         }
     }
 
@@ -28,3 +72,47 @@ async fn server_status(mut redis_conn: RedisClient) -> Result<(), Vec<String>> {
         Err(failing_servers)
     }
 }
+
+/// Folds a probe into the server's shared health.
+///
+/// Every balancer instance probes every server, so the health is only replaced if no
+/// other instance updated it since it was read, and the probe is applied again on top
+/// of the newer health otherwise.
+async fn update_health(
+    redis_conn: &mut RedisClient,
+    url: &str,
+    available: bool,
+    thresholds: &HealthThresholds,
+) -> Result<(), Error> {
+    for _ in 0..MAX_HEALTH_UPDATE_ATTEMPTS {
+        let stored = redis_conn.get_server_health(url).await?;
+        let previous = stored.unwrap_or_default();
+        let health = previous.record_probe(available, thresholds);
+
+        if !redis_conn
+            .compare_and_set_server_health(url, stored.as_ref(), &health)
+            .await?
+        {
+            continue;
+        }
+
+        if health.state != previous.state {
+            tracing::warn!(
+                "Server {} is now {:?} (was {:?})",
+                url,
+                health.state,
+                previous.state
+            );
+
+            if health.is_routable() {
+                redis_conn.mark_server_ready(url).await?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    Err(Error::Other(anyhow::anyhow!(
+        "health kept changing concurrently, probe dropped"
+    )))
+}